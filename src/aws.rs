@@ -0,0 +1,340 @@
+//! AWS SDK interceptor recording AWS service calls as X-Ray subsegments
+//!
+//! Available with the `aws-sdk` feature.
+
+use crate::{span_ext::XRaySpanExt, types::header::Header};
+use aws_smithy_runtime_api::{
+    box_error::BoxError,
+    client::{
+        interceptors::{
+            context::{
+                BeforeSerializationInterceptorContextRef, BeforeTransmitInterceptorContextMut,
+                FinalizerInterceptorContextRef,
+            },
+            Intercept,
+        },
+        orchestrator::{HttpRequest, Metadata},
+        runtime_components::RuntimeComponents,
+    },
+};
+use aws_smithy_types::{
+    config_bag::{ConfigBag, Storable, StoreReplace},
+    telemetry::{CapturedTelemetryAttributes, RequestedTelemetryAttributes},
+};
+use aws_types::region::Region;
+use serde_json::Value;
+use tracing::{field, Span};
+
+/// Response headers carrying the id AWS services assign to a request
+const REQUEST_ID_HEADERS: [&str; 2] = ["x-amzn-requestid", "x-amz-request-id"];
+
+/// Operation parameters recorded in the `aws` block, and their span fields
+const PARAMETERS: [(&str, &str); 2] = [
+    ("TableName", "aws.table_name"),
+    ("QueueUrl", "aws.queue_url"),
+];
+
+/// Services whose JSON request bodies are read for [`PARAMETERS`] when the
+/// SDK does not capture them from the operation input
+const JSON_SERVICES: [&str; 2] = ["DynamoDB", "SQS"];
+
+/// Largest request body read for [`PARAMETERS`]
+const MAX_PARSED_BODY: usize = 16 * 1024;
+
+/// Records each call made by an AWS SDK client as an `aws` subsegment of the
+/// current segment, named after the service called, and propagates the trace
+/// in the `X-Amzn-Trace-Id` request header
+///
+/// The subsegment's `aws` block records the operation, region, request id and
+/// number of retries, along with the `TableName` and `QueueUrl` parameters of
+/// DynamoDB and SQS requests.
+///
+/// ```no_run
+/// use tracing_xray::aws::XRayInterceptor;
+///
+/// let config = aws_sdk_dynamodb::Config::builder()
+///     .behavior_version_latest()
+///     .interceptor(XRayInterceptor::new())
+///     .build();
+/// let dynamodb = aws_sdk_dynamodb::Client::from_conf(config);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct XRayInterceptor {
+    _private: (),
+}
+
+impl XRayInterceptor {
+    /// Creates an interceptor recording calls as `aws` subsegments
+    pub fn new() -> Self {
+        XRayInterceptor::default()
+    }
+}
+
+/// The span recording a call, kept in the call's config bag
+#[derive(Debug)]
+struct CallSpan {
+    span: Span,
+    attempts: u32,
+}
+
+impl Storable for CallSpan {
+    type Storer = StoreReplace<Self>;
+}
+
+impl Intercept for XRayInterceptor {
+    fn name(&self) -> &'static str {
+        "XRayInterceptor"
+    }
+
+    fn read_before_execution(
+        &self,
+        _: &BeforeSerializationInterceptorContextRef<'_>,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        // the operation's metadata is not yet configured when execution
+        // begins, so it is recorded before the request is sent
+        let span = tracing::info_span!(
+            "aws",
+            xray.name = field::Empty,
+            xray.namespace = "aws",
+            aws.operation = field::Empty,
+            aws.region = field::Empty,
+            aws.request_id = field::Empty,
+            aws.retries = field::Empty,
+            aws.table_name = field::Empty,
+            aws.queue_url = field::Empty,
+            http.method = field::Empty,
+            http.url = field::Empty,
+            http.status_code = field::Empty,
+        );
+        cfg.interceptor_state()
+            .store_put(CallSpan { span, attempts: 0 });
+
+        // asks the SDK to capture the parameters from the operation input,
+        // which is consumed by the time the request is sent
+        let mut requested = cfg
+            .load::<RequestedTelemetryAttributes>()
+            .cloned()
+            .unwrap_or_default();
+        requested.capture_only(PARAMETERS.map(|(parameter, _)| parameter));
+        cfg.interceptor_state().store_put(requested);
+        Ok(())
+    }
+
+    fn modify_before_transmit(
+        &self,
+        context: &mut BeforeTransmitInterceptorContextMut<'_>,
+        _: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let metadata = cfg.load::<Metadata>().cloned();
+        let region = cfg.load::<Region>().cloned();
+        let captured = cfg.load::<CapturedTelemetryAttributes>().cloned();
+        let call = match cfg.get_mut_from_interceptor_state::<CallSpan>() {
+            Some(call) => call,
+            None => return Ok(()),
+        };
+        call.attempts += 1;
+        let span = &call.span;
+        if let Some(metadata) = &metadata {
+            span.record("xray.name", metadata.service());
+            span.record("aws.operation", metadata.name());
+        }
+        if let Some(region) = region {
+            span.record("aws.region", region.as_ref());
+        }
+
+        let request = context.request_mut();
+        span.record("http.method", request.method());
+        span.record("http.url", request.uri());
+        match captured {
+            Some(captured) => {
+                for (parameter, field) in PARAMETERS {
+                    if let Some(value) = captured.get(parameter) {
+                        span.record(field, value);
+                    }
+                }
+            }
+            // SDKs predating input capture only carry the parameters in the
+            // serialized request
+            None => {
+                let service = metadata.as_ref().map(Metadata::service);
+                if let Some(parameters) = json_parameters(service, request) {
+                    for (parameter, field) in PARAMETERS {
+                        if let Some(value) = parameters.get(parameter).and_then(Value::as_str) {
+                            span.record(field, value);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(header) = span.xray_trace_header() {
+            request
+                .headers_mut()
+                .insert(Header::NAME, header.to_string());
+        }
+        Ok(())
+    }
+
+    fn read_after_execution(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let call = match cfg.get_mut_from_interceptor_state::<CallSpan>() {
+            Some(call) => call,
+            None => return Ok(()),
+        };
+        let span = &call.span;
+        if call.attempts > 1 {
+            span.record("aws.retries", call.attempts - 1);
+        }
+        match context.response() {
+            Some(response) => {
+                span.record("http.status_code", response.status().as_u16());
+                let request_id = REQUEST_ID_HEADERS
+                    .iter()
+                    .find_map(|name| response.headers().get(*name));
+                if let Some(request_id) = request_id {
+                    span.record("aws.request_id", request_id);
+                }
+            }
+            None => span.xray_mark_fault(),
+        }
+        // closes the span, completing the subsegment
+        cfg.interceptor_state().unset::<CallSpan>();
+        Ok(())
+    }
+}
+
+/// Parses the body of a small JSON protocol request to one of
+/// [`JSON_SERVICES`]
+fn json_parameters(service: Option<&str>, request: &HttpRequest) -> Option<Value> {
+    if !service.is_some_and(|service| JSON_SERVICES.contains(&service)) {
+        return None;
+    }
+    let is_json = request
+        .headers()
+        .get("content-type")
+        .is_some_and(|content_type| content_type.starts_with("application/x-amz-json"));
+    let body = request.body().bytes()?;
+    if !is_json || body.len() > MAX_PARSED_BODY {
+        return None;
+    }
+    serde_json::from_slice(body).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sampling::DefaultSampler, MemoryEmitter, XRay};
+    use aws_sdk_dynamodb::{
+        config::{BehaviorVersion, Credentials, Region},
+        Client, Config,
+    };
+    use aws_smithy_http_client::test_util::{ReplayEvent, StaticReplayClient};
+    use aws_smithy_types::body::SdkBody;
+    use tracing::Instrument;
+    use tracing_subscriber::prelude::*;
+
+    #[tokio::test]
+    async fn records_aws_subsegment() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(
+            XRay::default()
+                .with_emitter(emitter.clone())
+                .with_sampler(DefaultSampler::new(0, 1.0)),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let http_client = StaticReplayClient::new(vec![ReplayEvent::new(
+            http::Request::builder()
+                .uri("https://dynamodb.eu-west-1.amazonaws.com/")
+                .body(SdkBody::empty())
+                .unwrap(),
+            http::Response::builder()
+                .status(200)
+                .header("x-amzn-requestid", "QF2A7KJM5NVO4RJH")
+                .body(SdkBody::from("{}"))
+                .unwrap(),
+        )]);
+        let config = Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("eu-west-1"))
+            .credentials_provider(Credentials::for_tests())
+            .http_client(http_client.clone())
+            .interceptor(XRayInterceptor::new())
+            .build();
+        Client::from_conf(config)
+            .get_item()
+            .table_name("orders")
+            .send()
+            .instrument(tracing::info_span!("handler"))
+            .await
+            .unwrap();
+
+        let document = &emitter.documents()[0];
+        let call = find_subsegment(document, "aws").expect("no aws subsegment");
+        assert_eq!(call["name"], "DynamoDB");
+        assert_eq!(
+            call["aws"],
+            serde_json::json!({
+                "operation": "GetItem",
+                "region": "eu-west-1",
+                "request_id": "QF2A7KJM5NVO4RJH",
+                "table_name": "orders",
+            })
+        );
+        assert_eq!(call["http"]["response"]["status"], 200);
+
+        let request = http_client.actual_requests().next().unwrap();
+        assert_eq!(
+            request.headers().get(Header::NAME),
+            Some(
+                format!(
+                    "Root={};Parent={};Sampled=1",
+                    document["trace_id"].as_str().unwrap(),
+                    call["id"].as_str().unwrap()
+                )
+                .as_str()
+            )
+        );
+    }
+
+    #[test]
+    fn parses_only_small_json_bodies_of_mapped_services() {
+        let request = |content_type: &str, body: String| {
+            let mut request = HttpRequest::new(SdkBody::from(body));
+            request
+                .headers_mut()
+                .insert("content-type", content_type.to_string());
+            request
+        };
+        let json = "application/x-amz-json-1.0";
+        let table = r#"{"TableName":"orders"}"#.to_string();
+
+        let parameters = json_parameters(Some("DynamoDB"), &request(json, table.clone()));
+        assert_eq!(parameters.unwrap()["TableName"], "orders");
+        assert!(json_parameters(Some("S3"), &request(json, table.clone())).is_none());
+        assert!(json_parameters(None, &request(json, table.clone())).is_none());
+        assert!(json_parameters(Some("SQS"), &request("text/xml", table)).is_none());
+        let large = format!(
+            r#"{{"TableName":"orders","Pad":"{}"}}"#,
+            "x".repeat(MAX_PARSED_BODY)
+        );
+        assert!(json_parameters(Some("DynamoDB"), &request(json, large)).is_none());
+    }
+
+    /// Finds the first subsegment within `document` in `namespace`, which may
+    /// be nested within the SDK's own spans
+    fn find_subsegment<'a>(document: &'a Value, namespace: &str) -> Option<&'a Value> {
+        document["subsegments"]
+            .as_array()?
+            .iter()
+            .find_map(|subsegment| match subsegment["namespace"].as_str() {
+                Some(found) if found == namespace => Some(subsegment),
+                _ => find_subsegment(subsegment, namespace),
+            })
+    }
+}
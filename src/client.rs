@@ -0,0 +1,33 @@
+//! Spans recording outbound HTTP requests, shared by the client middleware
+
+use crate::{span_ext::XRaySpanExt, types::header::Header};
+use http::{header::HeaderValue, HeaderMap};
+use tracing::{field, Span};
+
+/// Creates the span recording an outbound request as a remote subsegment
+pub(crate) fn call_span(method: &str, url: &str, host: Option<&str>) -> Span {
+    tracing::info_span!(
+        "call",
+        xray.name = host.unwrap_or("remote"),
+        xray.namespace = "remote",
+        http.method = method,
+        http.url = url,
+        http.traced = true,
+        http.status_code = field::Empty,
+    )
+}
+
+/// Adds the header continuing the trace of `span` to an outbound request
+pub(crate) fn inject_trace_header(span: &Span, headers: &mut HeaderMap) {
+    let trace_header = span
+        .xray_trace_header()
+        .and_then(|header| HeaderValue::try_from(header.to_string()).ok());
+    if let Some(value) = trace_header {
+        headers.insert(Header::NAME, value);
+    }
+}
+
+/// Records the status of the response to an outbound request
+pub(crate) fn record_status(span: &Span, status: u16) {
+    span.record("http.status_code", status);
+}
@@ -0,0 +1,66 @@
+//! Location of the X-Ray daemon
+//!
+//! The daemon receives segment documents over UDP and serves the sampling
+//! proxy over TCP, both on `127.0.0.1:2000` by default. The
+//! `AWS_XRAY_DAEMON_ADDRESS` environment variable overrides the address, using
+//! either the `host:port` form for both protocols or the
+//! `tcp:host:port udp:host:port` form to address them separately.
+
+use std::env;
+
+/// Address the daemon listens on when none is configured
+pub(crate) const DEFAULT_ADDRESS: &str = "127.0.0.1:2000";
+
+/// Environment variable used to override the daemon address
+pub(crate) const ADDRESS_ENV: &str = "AWS_XRAY_DAEMON_ADDRESS";
+
+/// The protocols served by the daemon
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Protocol {
+    /// Receives segment documents
+    Udp,
+    /// Serves the sampling proxy
+    Tcp,
+}
+
+/// Returns the daemon address for `protocol` found in the environment,
+/// falling back to [`DEFAULT_ADDRESS`]
+pub(crate) fn address(protocol: Protocol) -> String {
+    match env::var(ADDRESS_ENV) {
+        Ok(value) => parse_address(&value, protocol).into(),
+        Err(_) => DEFAULT_ADDRESS.into(),
+    }
+}
+
+/// Extracts the address for `protocol` from an `AWS_XRAY_DAEMON_ADDRESS` value
+fn parse_address(value: &str, protocol: Protocol) -> &str {
+    let prefix = match protocol {
+        Protocol::Udp => "udp:",
+        Protocol::Tcp => "tcp:",
+    };
+    value
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix(prefix))
+        .unwrap_or_else(|| value.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_daemon_address_forms() {
+        assert_eq!(
+            parse_address("127.0.0.1:3000", Protocol::Udp),
+            "127.0.0.1:3000"
+        );
+        assert_eq!(
+            parse_address("tcp:127.0.0.1:2000 udp:127.0.0.2:2001", Protocol::Udp),
+            "127.0.0.2:2001"
+        );
+        assert_eq!(
+            parse_address("tcp:127.0.0.1:2000 udp:127.0.0.2:2001", Protocol::Tcp),
+            "127.0.0.1:2000"
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use super::Emitter;
+use crate::{error::XRayError, types::types::Segment};
+use std::{
+    io::{self, Stdout, Write},
+    sync::Mutex,
+};
+
+/// Writes each segment document as a single line of JSON
+///
+/// Useful for local development, or for shipping segments through a log
+/// pipeline by way of a file.
+#[derive(Debug)]
+pub struct JsonLinesEmitter<W> {
+    writer: Mutex<W>,
+}
+
+impl JsonLinesEmitter<Stdout> {
+    /// Creates an emitter which writes to stdout
+    pub fn stdout() -> Self {
+        JsonLinesEmitter::new(io::stdout())
+    }
+}
+
+impl<W> JsonLinesEmitter<W>
+where
+    W: Write + Send + 'static,
+{
+    /// Creates an emitter which writes to `writer`
+    pub fn new(writer: W) -> Self {
+        JsonLinesEmitter {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Consumes the emitter, returning the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<W> Emitter for JsonLinesEmitter<W>
+where
+    W: Write + Send + 'static,
+{
+    fn send(&self, segment: &Segment) -> Result<(), XRayError> {
+        let mut line = serde_json::to_vec(segment)?;
+        line.push(b'\n');
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        writer.write_all(&line).map_err(XRayError::Emitter)
+    }
+
+    fn flush(&self) -> Result<(), XRayError> {
+        self.writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .flush()
+            .map_err(XRayError::Emitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_one_document_per_line() -> Result<(), XRayError> {
+        let emitter = JsonLinesEmitter::new(Vec::new());
+        emitter.send(&Segment::begin("first"))?;
+        emitter.send(&Segment::begin("second"))?;
+
+        let output = String::from_utf8(emitter.into_inner()).expect("output is not utf8");
+        let names = output
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).map(|doc| doc["name"].clone())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(names, vec!["first", "second"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use super::Emitter;
+use crate::{error::XRayError, types::types::Segment};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+
+/// Collects segment documents in memory
+///
+/// Clones share the same collection, so a clone may be handed to the layer
+/// while the original is kept to inspect what was emitted.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryEmitter {
+    documents: Arc<Mutex<Vec<Value>>>,
+}
+
+impl MemoryEmitter {
+    /// Creates an empty collector
+    pub fn new() -> Self {
+        MemoryEmitter::default()
+    }
+
+    /// Returns the segment documents emitted so far, in the order they were sent
+    pub fn documents(&self) -> Vec<Value> {
+        self.lock().clone()
+    }
+
+    /// Removes and returns the segment documents emitted so far
+    pub fn take(&self) -> Vec<Value> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Value>> {
+        self.documents
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Emitter for MemoryEmitter {
+    fn send(&self, segment: &Segment) -> Result<(), XRayError> {
+        let document = serde_json::to_value(segment)?;
+        self.lock().push(document);
+        Ok(())
+    }
+}
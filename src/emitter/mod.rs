@@ -0,0 +1,35 @@
+//! Delivery of finished segment documents
+//!
+//! The [`XRay`](crate::XRay) layer hands every closed segment to an [`Emitter`].
+//! [`UdpEmitter`] sends documents to the X-Ray daemon and is used by default,
+//! [`JsonLinesEmitter`] writes them to stdout or any other writer and
+//! [`MemoryEmitter`] collects them for inspection in tests.
+
+mod json_lines;
+mod memory;
+mod udp;
+
+pub use json_lines::JsonLinesEmitter;
+pub use memory::MemoryEmitter;
+pub use udp::UdpEmitter;
+
+use crate::{error::XRayError, types::types::Segment};
+
+/// Header line prefixed to every segment document sent to the daemon
+pub(crate) const DAEMON_HEADER: &str = "{\"format\": \"json\", \"version\": 1}\n";
+
+/// A destination for finished segments
+pub trait Emitter: Send + Sync + 'static {
+    /// Sends a single segment document
+    fn send(&self, segment: &Segment) -> Result<(), XRayError>;
+
+    /// Flushes any buffered segment documents
+    fn flush(&self) -> Result<(), XRayError> {
+        Ok(())
+    }
+
+    /// Flushes and releases the emitter's resources. Called when the layer is dropped
+    fn shutdown(&self) -> Result<(), XRayError> {
+        self.flush()
+    }
+}
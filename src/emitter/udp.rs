@@ -0,0 +1,170 @@
+use super::{Emitter, DAEMON_HEADER};
+use crate::{
+    daemon::{self, Protocol},
+    error::XRayError,
+    types::types::Segment,
+};
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+/// Sends segment documents to the X-Ray daemon over UDP
+///
+/// The daemon address defaults to `127.0.0.1:2000` and may be overridden with the
+/// `AWS_XRAY_DAEMON_ADDRESS` environment variable, using either the `host:port`
+/// form or the `tcp:host:port udp:host:port` form.
+///
+/// Documents too large for a single datagram are split, sending the segment's
+/// subsegments as documents of their own.
+#[derive(Debug)]
+pub struct UdpEmitter {
+    socket: UdpSocket,
+    address: SocketAddr,
+    max_datagram_size: usize,
+}
+
+impl UdpEmitter {
+    /// Address the daemon listens on when none is configured
+    pub const DEFAULT_ADDRESS: &'static str = daemon::DEFAULT_ADDRESS;
+
+    /// Environment variable used to override the daemon address
+    pub const ADDRESS_ENV: &'static str = daemon::ADDRESS_ENV;
+
+    /// Largest datagram sent by default, the most a UDP datagram over IPv4
+    /// can carry
+    pub const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+    /// Creates an emitter for the daemon address found in the environment,
+    /// falling back to [`UdpEmitter::DEFAULT_ADDRESS`]
+    pub fn from_env() -> io::Result<Self> {
+        Self::new(daemon::address(Protocol::Udp))
+    }
+
+    /// Creates an emitter for the daemon listening on `address`
+    pub fn new<A>(address: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no daemon address resolved")
+        })?;
+        let bind: SocketAddr = if address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        Ok(UdpEmitter {
+            socket,
+            address,
+            max_datagram_size: Self::MAX_DATAGRAM_SIZE,
+        })
+    }
+
+    /// Splits documents larger than `size` bytes, including the daemon
+    /// header, instead of [`UdpEmitter::MAX_DATAGRAM_SIZE`]
+    pub fn with_max_datagram_size(mut self, size: usize) -> Self {
+        self.max_datagram_size = size;
+        self
+    }
+
+    /// The address segment documents are sent to
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Emitter for UdpEmitter {
+    /// Serializes a segment and sends it to the daemon as a single datagram,
+    /// or as one datagram per subsegment if it is too large
+    fn send(&self, segment: &Segment) -> Result<(), XRayError> {
+        let mut datagram = DAEMON_HEADER.as_bytes().to_vec();
+        serde_json::to_writer(&mut datagram, segment)?;
+        if datagram.len() > self.max_datagram_size && !segment.subsegments.is_empty() {
+            // subsegments carry their trace and parent ids, so they can be
+            // sent apart from the segment which holds them
+            let mut document = serde_json::to_value(segment)?;
+            if let Some(fields) = document.as_object_mut() {
+                fields.remove("subsegments");
+            }
+            datagram.truncate(DAEMON_HEADER.len());
+            serde_json::to_writer(&mut datagram, &document)?;
+            self.socket
+                .send_to(&datagram, self.address)
+                .map_err(XRayError::Emitter)?;
+            return segment
+                .subsegments
+                .iter()
+                .try_for_each(|subsegment| self.send(subsegment));
+        }
+        self.socket
+            .send_to(&datagram, self.address)
+            .map_err(XRayError::Emitter)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn sends_segment_with_daemon_header() -> Result<(), Box<dyn std::error::Error>> {
+        let daemon = UdpSocket::bind("127.0.0.1:0")?;
+        daemon.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let emitter = UdpEmitter::new(daemon.local_addr()?)?;
+
+        let mut segment = Segment::begin("test");
+        segment.end();
+        emitter.send(&segment)?;
+
+        let mut buf = [0; 65_535];
+        let len = daemon.recv(&mut buf)?;
+        let datagram = std::str::from_utf8(&buf[..len]).expect("datagram is not utf8");
+        let body = datagram
+            .strip_prefix(DAEMON_HEADER)
+            .expect("datagram is missing daemon header");
+        let document: serde_json::Value = serde_json::from_str(body)?;
+        assert_eq!(document["name"], "test");
+        assert_eq!(document["id"], segment.id.to_string());
+        assert!(document["end_time"].is_f64());
+        Ok(())
+    }
+
+    #[test]
+    fn splits_large_segments() -> Result<(), Box<dyn std::error::Error>> {
+        let daemon = UdpSocket::bind("127.0.0.1:0")?;
+        daemon.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let emitter = UdpEmitter::new(daemon.local_addr()?)?.with_max_datagram_size(512);
+
+        let mut segment = Segment::begin("test");
+        for i in 0..3 {
+            let mut subsegment = Segment::begin_subsegment(format!("call-{}", i), &segment);
+            subsegment.end();
+            segment.subsegments.push(subsegment);
+        }
+        segment.end();
+        emitter.send(&segment)?;
+
+        let mut buf = [0; 65_535];
+        let mut documents = Vec::new();
+        for _ in 0..4 {
+            let len = daemon.recv(&mut buf)?;
+            assert!(len <= 512);
+            documents.push(serde_json::from_slice::<serde_json::Value>(
+                &buf[DAEMON_HEADER.len()..len],
+            )?);
+        }
+        assert_eq!(documents[0]["name"], "test");
+        assert!(documents[0].get("subsegments").is_none());
+        for (i, document) in documents[1..].iter().enumerate() {
+            assert_eq!(document["name"], format!("call-{}", i));
+            assert_eq!(document["type"], "subsegment");
+            assert_eq!(document["trace_id"], segment.trace_id.to_string());
+            assert_eq!(document["parent_id"], segment.id.to_string());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+//! Errors raised while recording and emitting segments
+
+use crate::types::ids::IdError;
+use std::{error::Error, fmt, io};
+
+/// An error encountered by the [`XRay`](crate::XRay) layer or one of its emitters
+///
+/// The layer never panics on these errors. Instead they are passed to the
+/// handler registered with [`XRay::with_error_handler`](crate::XRay::with_error_handler).
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum XRayError {
+    /// An `X-Amzn-Trace-Id` header could not be parsed
+    InvalidHeader(String),
+    /// A trace or segment id could not be parsed
+    InvalidId(IdError),
+    /// A segment document could not be serialized
+    Serialization(serde_json::Error),
+    /// An emitter failed to deliver a segment document
+    Emitter(io::Error),
+    /// A sampling rules document could not be loaded
+    InvalidSamplingRules(String),
+    /// A request to the daemon's sampling proxy failed
+    SamplingProxy(String),
+    /// A span could not be found in the subscriber's registry
+    MissingSpan,
+    /// A span, identified by name, does not carry an X-Ray segment. This
+    /// happens for spans created before the layer was installed
+    MissingSegment(&'static str),
+}
+
+impl fmt::Display for XRayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XRayError::InvalidHeader(reason) => write!(f, "invalid trace header: {}", reason),
+            XRayError::InvalidId(e) => write!(f, "invalid id: {}", e),
+            XRayError::Serialization(e) => write!(f, "failed to serialize segment: {}", e),
+            XRayError::Emitter(e) => write!(f, "failed to emit segment: {}", e),
+            XRayError::InvalidSamplingRules(reason) => {
+                write!(f, "invalid sampling rules: {}", reason)
+            }
+            XRayError::SamplingProxy(reason) => {
+                write!(f, "sampling proxy request failed: {}", reason)
+            }
+            XRayError::MissingSpan => write!(f, "span does not exist in the registry"),
+            XRayError::MissingSegment(name) => {
+                write!(f, "span `{}` does not have an XRay segment", name)
+            }
+        }
+    }
+}
+
+impl Error for XRayError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            XRayError::InvalidId(e) => Some(e),
+            XRayError::Serialization(e) => Some(e),
+            XRayError::Emitter(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<IdError> for XRayError {
+    fn from(e: IdError) -> Self {
+        XRayError::InvalidId(e)
+    }
+}
+
+impl From<serde_json::Error> for XRayError {
+    fn from(e: serde_json::Error) -> Self {
+        XRayError::Serialization(e)
+    }
+}
@@ -1,35 +1,295 @@
 use serde::{Deserialize, Serialize};
+use std::{any::TypeId, io, sync::Mutex};
 use tracing::{
-    span::{Attributes, Id},
-    Subscriber,
+    span::{Attributes, Id, Record},
+    Dispatch, Event, Level, Subscriber,
 };
 use tracing_subscriber::{
+    field::RecordFields,
     layer::{Context, Layer},
-    registry::LookupSpan,
+    registry::{LookupSpan, SpanRef},
 };
 
+#[cfg(feature = "aws-sdk")]
+pub mod aws;
+#[cfg(any(feature = "tower", feature = "reqwest-middleware"))]
+mod client;
+mod daemon;
+mod emitter;
+mod error;
+pub mod model;
+#[cfg(feature = "reqwest-middleware")]
+pub mod reqwest;
+pub mod sampling;
+mod span_ext;
+#[cfg(feature = "tower")]
+pub mod tower;
 mod types;
+mod visit;
+pub use crate::emitter::{Emitter, JsonLinesEmitter, MemoryEmitter, UdpEmitter};
+pub use crate::error::XRayError;
+use crate::sampling::{DefaultSampler, Sampler, SamplingRequest};
+use crate::span_ext::WithSegment;
+pub use crate::span_ext::XRaySpanExt;
 use crate::types::header::{Header, SamplingDecision};
+use crate::types::types::Segment;
+use crate::visit::{
+    AwsVisitor, EventVisitor, FieldMapping, FieldVisitor, HeaderVisitor, HttpVisitor, SqlVisitor,
+};
 use types::{
     ids::{SegmentId, TraceId},
     time::Seconds,
-    types::Segment,
 };
 
-type Err = Box<dyn std::error::Error + Send + Sync + 'static>;
+pub(crate) type ErrorHandler = Box<dyn Fn(XRayError) + Send + Sync + 'static>;
 
-#[derive(Default)]
 pub struct XRay {
+    origin: Option<String>,
     resource_arn: Option<String>,
+    emitter: Option<Box<dyn Emitter>>,
+    /// Why the default emitter could not be created, reported once
+    emitter_error: Mutex<Option<XRayError>>,
+    trace_header_field: String,
+    fields: FieldMapping,
+    event_level: Option<Level>,
+    max_events: usize,
+    sampler: Box<dyn Sampler>,
+    streaming_threshold: usize,
+    error_handler: ErrorHandler,
+    with_segment: Option<WithSegment>,
+}
+
+impl Default for XRay {
+    /// Creates a layer which sends segments to the daemon address found in
+    /// the environment
+    ///
+    /// If no emitter can be created for the address, the error is passed to
+    /// the error handler in place of the first segment. Use
+    /// [`XRay::from_env`] to fail instead.
+    fn default() -> Self {
+        XRay::new(UdpEmitter::from_env())
+    }
 }
 
 impl XRay {
-    pub fn with_resource_arn(self, arn: String) -> XRay {
+    /// Creates a layer which sends segments to the daemon address found in
+    /// the environment, failing if no emitter can be created for it
+    pub fn from_env() -> Result<XRay, XRayError> {
+        let emitter = UdpEmitter::from_env().map_err(XRayError::Emitter)?;
+        Ok(XRay::new(Ok(emitter)))
+    }
+
+    fn new(emitter: io::Result<UdpEmitter>) -> XRay {
+        let (emitter, emitter_error) = match emitter {
+            Ok(emitter) => (Some(Box::new(emitter) as Box<dyn Emitter>), None),
+            Err(e) => (None, Some(XRayError::Emitter(e))),
+        };
         XRay {
-            resource_arn: Some(arn),
+            origin: None,
+            resource_arn: None,
+            trace_header_field: Header::NAME.into(),
+            fields: FieldMapping::default(),
+            event_level: Some(Level::WARN),
+            max_events: DEFAULT_MAX_EVENTS,
+            sampler: Box::new(DefaultSampler::default()),
+            streaming_threshold: DEFAULT_STREAMING_THRESHOLD,
+            with_segment: None,
+            error_handler: Box::new(|e| eprintln!("tracing-xray: {}", e)),
+            emitter,
+            emitter_error: Mutex::new(emitter_error),
+        }
+    }
+
+    pub fn with_resource_arn(mut self, arn: String) -> XRay {
+        self.resource_arn = Some(arn);
+        self
+    }
+
+    /// Records `origin`, the type of AWS resource running the application
+    /// such as `AWS::EC2::Instance`, on every segment. Centralized sampling
+    /// rules match it against their service type
+    pub fn with_origin<O>(mut self, origin: O) -> XRay
+    where
+        O: Into<String>,
+    {
+        self.origin = Some(origin.into());
+        self
+    }
+
+    /// Reads upstream trace context from the span field named `field` rather
+    /// than the default `x-amzn-trace-id`
+    ///
+    /// Field names are matched ignoring case and treating `_` and `-` as equal.
+    /// A span carrying the field begins a segment continuing the upstream
+    /// trace, even when nested in another span. The field must be recorded
+    /// when the span is created: values recorded later are ignored, as the
+    /// span's trace has already begun.
+    pub fn with_trace_header_field<F>(mut self, field: F) -> XRay
+    where
+        F: Into<String>,
+    {
+        self.trace_header_field = field.into();
+        self
+    }
+
+    /// Records span fields starting with `annotation_prefix` as annotations
+    /// and those starting with `metadata_prefix` as metadata, keyed by the
+    /// rest of the field name
+    ///
+    /// The prefixes default to `xray.annotation.` and `xray.metadata.`, so a
+    /// span field `xray.annotation.customer_id` becomes the annotation
+    /// `customer_id`. Strings, numbers and booleans are recorded as
+    /// annotations, values recorded with `?` or `%` as metadata.
+    pub fn with_field_prefixes<A, M>(mut self, annotation_prefix: A, metadata_prefix: M) -> XRay
+    where
+        A: Into<String>,
+        M: Into<String>,
+    {
+        self.fields.annotation_prefix = annotation_prefix.into();
+        self.fields.metadata_prefix = metadata_prefix.into();
+        self
+    }
+
+    /// Records the span fields named in `fields` as annotations, keyed by
+    /// their field name
+    pub fn with_annotation_fields<I, F>(mut self, fields: I) -> XRay
+    where
+        I: IntoIterator<Item = F>,
+        F: Into<String>,
+    {
+        self.fields
+            .annotation_fields
+            .extend(fields.into_iter().map(Into::into));
+        self
+    }
+
+    /// Attaches events at or above `level` to the segment of the span they
+    /// occur in, or none at all if `level` is `None`. Defaults to `WARN`
+    ///
+    /// Events are recorded as metadata in the `tracing` namespace. `ERROR`
+    /// events with an `error` field are instead recorded as exceptions in the
+    /// segment's `cause`, marking the segment as faulted.
+    pub fn with_event_level(mut self, level: Option<Level>) -> XRay {
+        self.event_level = level;
+        self
+    }
+
+    /// Keeps at most `max` events in the metadata of each segment, counting
+    /// any further events as `dropped`. Defaults to 50
+    ///
+    /// Events count towards the size of a segment document, which must fit
+    /// in a single datagram to reach the daemon.
+    pub fn with_max_events(mut self, max: usize) -> XRay {
+        self.max_events = max;
+        self
+    }
+
+    /// Sends closed segments with the provided emitter instead of the
+    /// default UDP daemon emitter
+    pub fn with_emitter<E>(mut self, emitter: E) -> XRay
+    where
+        E: Emitter,
+    {
+        self.emitter = Some(Box::new(emitter));
+        self.emitter_error = Mutex::new(None);
+        self
+    }
+
+    /// Decides which new traces are recorded with `sampler` instead of the
+    /// X-Ray default rule
+    ///
+    /// The sampler is consulted for root spans without an upstream sampling
+    /// decision. Nested spans follow the decision made for their root.
+    pub fn with_sampler<T>(mut self, sampler: T) -> XRay
+    where
+        T: Sampler,
+    {
+        self.sampler = Box::new(sampler);
+        self
+    }
+
+    /// Sends completed subsegments as documents of their own once the
+    /// document of their root segment holds more than `threshold` of them, at
+    /// any depth. Defaults to 100
+    ///
+    /// Long running requests with many child spans would otherwise produce a
+    /// single document too large for the daemon to accept. Streamed
+    /// subsegments carry their trace id and parent id, so X-Ray reassembles
+    /// them into the trace.
+    pub fn with_streaming_threshold(mut self, threshold: usize) -> XRay {
+        self.streaming_threshold = threshold;
+        self
+    }
+
+    /// Handles errors encountered while recording or emitting segments with
+    /// `handler` rather than printing them to stderr
+    ///
+    /// The layer never panics, so this is the place to log or count failures
+    /// such as malformed upstream headers or an unreachable daemon.
+    pub fn with_error_handler<F>(mut self, handler: F) -> XRay
+    where
+        F: Fn(XRayError) + Send + Sync + 'static,
+    {
+        self.error_handler = Box::new(handler);
+        self
+    }
+
+    fn handle_error(&self, e: XRayError) {
+        (self.error_handler)(e)
+    }
+
+    /// Records span fields onto the span's segment
+    fn record_fields<R>(&self, values: &R, segment: &mut Segment)
+    where
+        R: RecordFields,
+    {
+        values.record(&mut FieldVisitor::new(&self.fields, segment));
+        values.record(&mut HttpVisitor::new(segment));
+        values.record(&mut AwsVisitor::new(segment));
+        values.record(&mut SqlVisitor::new(segment));
+    }
+
+    /// Runs `f` against the segment of the span `id`, for spans reaching
+    /// their segment through [`XRaySpanExt`]
+    fn with_segment<S>(
+        dispatch: &Dispatch,
+        id: &Id,
+        f: &mut dyn FnMut(&mut Segment, SamplingDecision),
+    ) where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let span = dispatch
+            .downcast_ref::<S>()
+            .and_then(|subscriber| subscriber.span(id));
+        if let Some(span) = span {
+            // the segment is taken out of the span while `f` runs, so that
+            // events recorded by `f` don't wait on the extensions lock
+            let (segment, decision) = {
+                let mut ext = span.extensions_mut();
+                let decision = ext
+                    .get_mut::<SamplingDecision>()
+                    .map(|decision| *decision)
+                    .unwrap_or_default();
+                (ext.remove::<Segment>(), decision)
+            };
+            if let Some(mut segment) = segment {
+                f(&mut segment, decision);
+                span.extensions_mut().insert(segment);
+            }
         }
     }
 }
+
+impl Drop for XRay {
+    fn drop(&mut self) {
+        if let Some(emitter) = &self.emitter {
+            if let Err(e) = emitter.shutdown() {
+                self.handle_error(e);
+            }
+        }
+    }
+}
+#[allow(dead_code)]
 #[derive(Default, Debug, Serialize, Deserialize)]
 struct SharedData {
     pub(crate) trace_id: TraceId,
@@ -53,6 +313,7 @@ struct SharedData {
     pub(crate) state: State,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 enum State {
@@ -75,7 +336,7 @@ impl Default for State {
 }
 
 #[test]
-fn test_shared_data_representation() -> Result<(), Err> {
+fn test_shared_data_representation() -> Result<(), XRayError> {
     let mut data = SharedData::default();
     dbg!(serde_json::to_string(&data)?);
     data.state = State::Done {
@@ -89,56 +350,879 @@ impl<S> Layer<S> for XRay
 where
     S: Subscriber + for<'span> LookupSpan<'span>,
 {
+    fn on_layer(&mut self, _: &mut S) {
+        self.with_segment = Some(WithSegment(XRay::with_segment::<S>));
+    }
+
     fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return self.handle_error(XRayError::MissingSpan),
+        };
         let name = attrs.metadata().name();
-        let mut data = Segment::begin(name);
 
         // in lambda context there should be a facade header that is the root of the execution
         // if it exists use it to set the trace id and parent id
-        // the parent id will be overridden later when on_follows_from is called
-        if let Some(id) = attrs.metadata().fields().field("x-amzn-trace-id") {
-            let header = id
-                .to_string()
-                .parse::<Header>()
-                .expect("Unstable to parse header");
-            if header.sampling_decision.eq(&SamplingDecision::NotSampled) {
-                return;
+        // the parent id will be overridden later when on_follows_from is called.
+        // a span carrying a header begins a segment continuing the upstream
+        // trace even when nested in another span, such as a middleware's
+        // request span inside an application span. a malformed header is
+        // reported and the span is recorded as if it carried none
+        let mut visitor = HeaderVisitor::new(&self.trace_header_field);
+        attrs.record(&mut visitor);
+        let header = match visitor.header() {
+            Some(Ok(header)) => Some(header),
+            Some(Err(e)) => {
+                self.handle_error(e);
+                None
+            }
+            None => None,
+        };
+
+        // spans nested within a span carrying a segment are recorded as
+        // subsegments of that segment, sharing its trace id and sampling
+        // decision. the registry resolves both explicit (`parent: ...`) and
+        // contextual parents, so walking the span's scope covers either case
+        if header.is_none() {
+            if let Some(parent) = enclosing_segment(&span) {
+                let parent_ext = parent.extensions();
+                if let Some(parent_data) = parent_ext.get::<Segment>() {
+                    let mut data = Segment::begin_subsegment(name, parent_data);
+                    self.record_fields(attrs, &mut data);
+                    let decision = parent_ext
+                        .get::<SamplingDecision>()
+                        .copied()
+                        .unwrap_or_default();
+                    drop(parent_ext);
+                    let mut ext = span.extensions_mut();
+                    ext.insert(data);
+                    ext.insert(decision);
+                    return;
+                }
             }
-            data.trace_id = header.trace_id;
-            data.parent_id = header.parent_id;
         }
+
+        let (mut data, mut decision) = match header {
+            Some(header) => (
+                Segment::begin_from_header(name, &header),
+                header.sampling_decision,
+            ),
+            None => (Segment::begin(name), SamplingDecision::Unknown),
+        };
+        data.origin = self.origin.clone();
         data.resource_arn = self.resource_arn.clone();
+        self.record_fields(attrs, &mut data);
 
-        let span = ctx
-            .span(id)
-            .expect("in on_new_span but span does not exist");
-        span.extensions_mut().insert(data);
+        // a `Sampled=?` header defers the decision to this service, which
+        // reports it back upstream through `XRaySpanExt::xray_response_header`
+        if matches!(
+            decision,
+            SamplingDecision::Unknown | SamplingDecision::Requested
+        ) {
+            decision = self.sampler.sample(&SamplingRequest::for_segment(&data));
+        }
+
+        // unsampled segments are still tracked so that their children inherit
+        // the trace id and sampling decision, but they are never emitted
+        let mut ext = span.extensions_mut();
+        ext.insert(data);
+        ext.insert(decision);
     }
 
-    fn on_follows_from(&self, id: &Id, follows: &Id, ctx: Context<S>) {
-        let span = ctx.span(id).expect("Span not found, this is a bug");
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return self.handle_error(XRayError::MissingSpan),
+        };
         let mut ext = span.extensions_mut();
-        let data = ext
-            .get_mut::<Segment>()
-            .expect("span does not have XRay segment");
+        match ext.get_mut::<Segment>() {
+            Some(data) => self.record_fields(values, data),
+            None => self.handle_error(XRayError::MissingSegment(span.name())),
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        if self.event_level.is_none_or(|threshold| level > threshold) {
+            return;
+        }
+        // events outside of any recorded span have nowhere to go
+        let span = match ctx.event_span(event) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut ext = span.extensions_mut();
+        let data = match ext.get_mut::<Segment>() {
+            Some(data) => data,
+            None => return,
+        };
+
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        match visitor.error {
+            Some(exceptions) if level == Level::ERROR => {
+                data.fault = true;
+                data.add_exceptions(exceptions);
+            }
+            _ => {
+                let mut fields = visitor.fields;
+                fields.insert("timestamp".into(), Seconds::now().0.into());
+                fields.insert("level".into(), level.as_str().into());
+                fields.insert("target".into(), event.metadata().target().into());
+                let namespace = data
+                    .metadata
+                    .get_or_insert_with(Default::default)
+                    .entry(EVENTS_NAMESPACE.into())
+                    .or_insert_with(|| serde_json::json!({ "events": [] }));
+                if let Some(namespace) = namespace.as_object_mut() {
+                    let events = namespace
+                        .get_mut("events")
+                        .and_then(serde_json::Value::as_array_mut)
+                        .filter(|events| events.len() < self.max_events);
+                    match events {
+                        Some(events) => events.push(fields.into()),
+                        None => {
+                            let dropped = namespace
+                                .get("dropped")
+                                .and_then(serde_json::Value::as_u64)
+                                .unwrap_or_default();
+                            namespace.insert("dropped".into(), (dropped + 1).into());
+                        }
+                    }
+                }
+            }
+        }
+    }
 
-        let follows_span = ctx
-            .span(follows)
-            .expect("Span to follow not found, this is a bug");
+    fn on_follows_from(&self, id: &Id, follows: &Id, ctx: Context<S>) {
+        let (span, follows_span) = match (ctx.span(id), ctx.span(follows)) {
+            (Some(span), Some(follows_span)) => (span, follows_span),
+            _ => return self.handle_error(XRayError::MissingSpan),
+        };
         let follows_ext = follows_span.extensions();
-        let follows_data = follows_ext
-            .get::<Segment>()
-            .expect("span does not have XRay segment");
+        let follows_data = match follows_ext.get::<Segment>() {
+            Some(data) => data,
+            None => return self.handle_error(XRayError::MissingSegment(follows_span.name())),
+        };
 
-        data.parent_id = Some(follows_data.id.clone());
+        let mut ext = span.extensions_mut();
+        match ext.get_mut::<Segment>() {
+            Some(data) => data.parent_id = Some(follows_data.id),
+            None => self.handle_error(XRayError::MissingSegment(span.name())),
+        }
     }
 
     fn on_close(&self, id: Id, ctx: Context<S>) {
-        let span = ctx.span(&id).expect("in on_close but span does not exist");
-        let mut ext = span.extensions_mut();
-        let data = ext
-            .get_mut::<Segment>()
-            .expect("span does not have XRay segment");
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return self.handle_error(XRayError::MissingSpan),
+        };
+        let mut data = match span.extensions_mut().remove::<Segment>() {
+            Some(data) => data,
+            None => return self.handle_error(XRayError::MissingSegment(span.name())),
+        };
         data.end();
+
+        if span.extensions().get::<SamplingDecision>() == Some(&SamplingDecision::NotSampled) {
+            return;
+        }
+
+        // subsegments are embedded in the document of their parent segment,
+        // which is sent once the root segment closes, unless the document
+        // holds enough of them to be streamed ahead
+        let segments = if data.is_subsegment() {
+            enclosing_segments(&span)
+        } else {
+            Vec::new()
+        };
+        let (parent, root) = match (segments.first(), segments.last()) {
+            (Some(parent), Some(root)) => (parent, root),
+            _ => return self.emit(&data),
+        };
+        match parent.extensions_mut().get_mut::<Segment>() {
+            Some(parent_data) => parent_data.subsegments.push(data),
+            None => return self.emit(&data),
+        }
+        let held = match root.extensions_mut().get_mut::<Segment>() {
+            Some(root_data) => {
+                root_data.held_subsegments += 1;
+                root_data.held_subsegments
+            }
+            None => return,
+        };
+        if held <= self.streaming_threshold {
+            return;
+        }
+
+        // only the segments enclosing the closed span are open here, so the
+        // subsegments they hold are streamed. those held by other open
+        // branches of the document are streamed as their own spans close
+        let mut streamed = Vec::new();
+        for segment in &segments {
+            if let Some(segment_data) = segment.extensions_mut().get_mut::<Segment>() {
+                streamed.append(&mut segment_data.subsegments);
+            }
+        }
+        let count: usize = streamed
+            .iter()
+            .map(|subsegment| 1 + subsegment.subsegment_count())
+            .sum();
+        if let Some(root_data) = root.extensions_mut().get_mut::<Segment>() {
+            root_data.held_subsegments = root_data.held_subsegments.saturating_sub(count);
+        }
+        for subsegment in &streamed {
+            self.emit(subsegment);
+        }
+    }
+
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        match id {
+            id if id == TypeId::of::<Self>() => Some(self as *const _ as *const ()),
+            id if id == TypeId::of::<WithSegment>() => self
+                .with_segment
+                .as_ref()
+                .map(|with_segment| with_segment as *const _ as *const ()),
+            _ => None,
+        }
+    }
+}
+
+impl XRay {
+    fn emit(&self, segment: &Segment) {
+        match &self.emitter {
+            Some(emitter) => {
+                if let Err(e) = emitter.send(segment) {
+                    self.handle_error(e);
+                }
+            }
+            None => {
+                let error = self
+                    .emitter_error
+                    .lock()
+                    .ok()
+                    .and_then(|mut error| error.take());
+                if let Some(e) = error {
+                    self.handle_error(e);
+                }
+            }
+        }
+    }
+}
+
+/// Completed subsegments a segment may hold before they are streamed
+const DEFAULT_STREAMING_THRESHOLD: usize = 100;
+
+/// Events a segment may hold in its metadata
+const DEFAULT_MAX_EVENTS: usize = 50;
+
+/// Metadata namespace events are recorded in
+const EVENTS_NAMESPACE: &str = "tracing";
+
+/// Finds the spans enclosing `span` which carry segments of the same
+/// document, from its parent up to the root segment
+fn enclosing_segments<'a, R>(span: &SpanRef<'a, R>) -> Vec<SpanRef<'a, R>>
+where
+    R: LookupSpan<'a>,
+{
+    let mut segments = Vec::new();
+    for ancestor in span.scope().skip(1) {
+        let is_root = match ancestor.extensions().get::<Segment>() {
+            Some(segment) => !segment.is_subsegment(),
+            None => continue,
+        };
+        segments.push(ancestor);
+        if is_root {
+            break;
+        }
+    }
+    segments
+}
+
+/// Finds the nearest span enclosing `span` which carries a segment
+fn enclosing_segment<'a, R>(span: &SpanRef<'a, R>) -> Option<SpanRef<'a, R>>
+where
+    R: LookupSpan<'a>,
+{
+    span.scope()
+        .skip(1)
+        .find(|ancestor| ancestor.extensions().get::<Segment>().is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::UdpSocket, time::Duration};
+    use tracing_subscriber::prelude::*;
+
+    /// A layer which records every trace into `emitter`
+    fn layer(emitter: &MemoryEmitter) -> XRay {
+        XRay::default()
+            .with_emitter(emitter.clone())
+            .with_sampler(DefaultSampler::new(0, 1.0))
+    }
+
+    #[test]
+    fn emits_closed_spans_to_daemon() -> Result<(), Box<dyn std::error::Error>> {
+        let daemon = UdpSocket::bind("127.0.0.1:0")?;
+        daemon.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let layer = XRay::default()
+            .with_emitter(UdpEmitter::new(daemon.local_addr()?)?)
+            .with_sampler(DefaultSampler::new(0, 1.0));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").in_scope(|| {});
+        });
+
+        let mut buf = [0; 65_535];
+        let len = daemon.recv(&mut buf)?;
+        let datagram = std::str::from_utf8(&buf[..len]).expect("datagram is not utf8");
+        let body = datagram
+            .strip_prefix(emitter::DAEMON_HEADER)
+            .expect("datagram is missing daemon header");
+        let document: serde_json::Value = serde_json::from_str(body)?;
+        assert_eq!(document["name"], "handler");
+        assert!(document.get("in_progress").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn emits_closed_spans_to_custom_emitter() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(layer(&emitter));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("first").in_scope(|| {});
+            tracing::info_span!("second").in_scope(|| {});
+        });
+
+        let names = emitter
+            .documents()
+            .into_iter()
+            .map(|document| document["name"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn embeds_nested_spans_as_subsegments() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(layer(&emitter));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").in_scope(|| {
+                tracing::info_span!("query").in_scope(|| {
+                    tracing::info_span!("decode").in_scope(|| {});
+                });
+            });
+        });
+
+        let documents = emitter.documents();
+        assert_eq!(documents.len(), 1);
+        let root = &documents[0];
+        assert_eq!(root["name"], "handler");
+        assert!(root.get("type").is_none());
+
+        let query = &root["subsegments"][0];
+        assert_eq!(query["name"], "query");
+        assert_eq!(query["type"], "subsegment");
+        assert_eq!(query["trace_id"], root["trace_id"]);
+        assert_eq!(query["parent_id"], root["id"]);
+
+        let decode = &query["subsegments"][0];
+        assert_eq!(decode["name"], "decode");
+        assert_eq!(decode["trace_id"], root["trace_id"]);
+        assert_eq!(decode["parent_id"], query["id"]);
+    }
+
+    #[test]
+    fn streams_subsegments_over_threshold() {
+        let emitter = MemoryEmitter::new();
+        let layer = layer(&emitter).with_streaming_threshold(2);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").in_scope(|| {
+                for i in 0..4 {
+                    tracing::info_span!("call", i).in_scope(|| {});
+                }
+            });
+        });
+
+        let documents = emitter.documents();
+        assert_eq!(documents.len(), 4);
+        let root = &documents[3];
+        assert_eq!(root["name"], "handler");
+        assert_eq!(root["subsegments"].as_array().map(Vec::len), Some(1));
+        for call in &documents[..3] {
+            assert_eq!(call["name"], "call");
+            assert_eq!(call["type"], "subsegment");
+            assert_eq!(call["trace_id"], root["trace_id"]);
+            assert_eq!(call["parent_id"], root["id"]);
+        }
+    }
+
+    /// The number of subsegments embedded in a document, at any depth
+    fn held_subsegments(document: &serde_json::Value) -> usize {
+        document["subsegments"]
+            .as_array()
+            .map(|subsegments| {
+                subsegments
+                    .iter()
+                    .map(|subsegment| 1 + held_subsegments(subsegment))
+                    .sum()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Asserts that no document exceeds `threshold` subsegments and returns
+    /// the number of subsegments sent, streamed or embedded
+    fn assert_streamed(documents: &[serde_json::Value], threshold: usize) -> usize {
+        let mut sent = 0;
+        for document in documents {
+            let held = held_subsegments(document);
+            assert!(
+                held <= threshold,
+                "{} holds {} subsegments",
+                document["name"],
+                held
+            );
+            sent += held + usize::from(document["type"] == "subsegment");
+        }
+        sent
+    }
+
+    #[test]
+    fn streams_subsegments_of_deep_trees() {
+        let emitter = MemoryEmitter::new();
+        let layer = layer(&emitter).with_streaming_threshold(4);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        // each level holds fewer subsegments than the threshold, but
+        // together they exceed it before any of the nested spans close
+        fn level(emitter: &MemoryEmitter, depth: usize) {
+            for _ in 0..2 {
+                tracing::info_span!("call").in_scope(|| {});
+            }
+            match depth {
+                0 => assert!(!emitter.documents().is_empty()),
+                _ => tracing::info_span!("nested").in_scope(|| level(emitter, depth - 1)),
+            }
+        }
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").in_scope(|| level(&emitter, 3));
+        });
+
+        let documents = emitter.documents();
+        assert_eq!(
+            documents.last().map(|root| &root["name"]),
+            Some(&"handler".into())
+        );
+        assert_eq!(assert_streamed(&documents, 4), 11);
+    }
+
+    #[test]
+    fn streams_subsegments_of_wide_trees() {
+        let emitter = MemoryEmitter::new();
+        let layer = layer(&emitter).with_streaming_threshold(4);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        // sibling parents which are open at the same time each hold fewer
+        // subsegments than the threshold, but together they exceed it
+        tracing::subscriber::with_default(subscriber, || {
+            let handler = tracing::info_span!("handler");
+            let parents: Vec<_> = (0..3)
+                .map(|_| tracing::info_span!(parent: &handler, "parent"))
+                .collect();
+            for parent in &parents {
+                parent.in_scope(|| {
+                    for _ in 0..3 {
+                        tracing::info_span!("call").in_scope(|| {});
+                    }
+                });
+            }
+            assert!(!emitter.documents().is_empty());
+        });
+
+        let documents = emitter.documents();
+        assert_eq!(
+            documents.last().map(|root| &root["name"]),
+            Some(&"handler".into())
+        );
+        assert_eq!(assert_streamed(&documents, 4), 12);
+    }
+
+    #[test]
+    fn inherits_trace_from_explicit_parent() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(layer(&emitter));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let handler = tracing::info_span!("handler");
+            tracing::info_span!(parent: &handler, "worker").in_scope(|| {
+                tracing::info_span!(parent: None, "detached").in_scope(|| {});
+            });
+        });
+
+        let documents = emitter.documents();
+        assert_eq!(documents.len(), 2);
+        let (detached, handler) = (&documents[0], &documents[1]);
+        assert_eq!(detached["name"], "detached");
+        assert!(detached.get("parent_id").is_none());
+        assert_ne!(detached["trace_id"], handler["trace_id"]);
+
+        let worker = &handler["subsegments"][0];
+        assert_eq!(worker["name"], "worker");
+        assert_eq!(worker["trace_id"], handler["trace_id"]);
+        assert_eq!(worker["parent_id"], handler["id"]);
+    }
+
+    #[test]
+    fn continues_trace_from_header_field() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(layer(&emitter));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!(
+                "sampled",
+                "x-amzn-trace-id" =
+                    "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1"
+            )
+            .in_scope(|| {});
+            tracing::info_span!(
+                "not_sampled",
+                x_amzn_trace_id = "Root=1-5759e988-bd862e3fe1be46a994272794;Sampled=0"
+            )
+            .in_scope(|| {
+                tracing::info_span!("child").in_scope(|| {});
+            });
+        });
+
+        let documents = emitter.documents();
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0]["name"], "sampled");
+        assert_eq!(
+            documents[0]["trace_id"],
+            "1-5759e988-bd862e3fe1be46a994272793"
+        );
+        assert_eq!(documents[0]["parent_id"], "53995c3f42cd8ad8");
+    }
+
+    #[test]
+    fn continues_trace_from_nested_header_field() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(layer(&emitter));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("app").in_scope(|| {
+                let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8";
+                tracing::info_span!("request", "x-amzn-trace-id" = header).in_scope(|| {
+                    tracing::info_span!("query").in_scope(|| {});
+                });
+            });
+        });
+
+        let documents = emitter.documents();
+        assert_eq!(documents.len(), 2);
+        let (request, app) = (&documents[0], &documents[1]);
+        assert_eq!(request["name"], "request");
+        assert!(request.get("type").is_none());
+        assert_eq!(request["trace_id"], "1-5759e988-bd862e3fe1be46a994272793");
+        assert_eq!(request["parent_id"], "53995c3f42cd8ad8");
+        assert_eq!(request["subsegments"][0]["name"], "query");
+        assert_eq!(request["subsegments"][0]["parent_id"], request["id"]);
+        assert_eq!(app["name"], "app");
+        assert!(app.get("subsegments").is_none());
+    }
+
+    #[test]
+    fn reads_configured_header_field() {
+        let emitter = MemoryEmitter::new();
+        let layer = layer(&emitter).with_trace_header_field("upstream");
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let header = "Root=1-5759e988-bd862e3fe1be46a994272793";
+            tracing::info_span!("handler", upstream = %header).in_scope(|| {});
+        });
+
+        assert_eq!(
+            emitter.documents()[0]["trace_id"],
+            "1-5759e988-bd862e3fe1be46a994272793"
+        );
+    }
+
+    #[test]
+    fn reports_malformed_headers_without_panicking() {
+        let emitter = MemoryEmitter::new();
+        let errors = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reported = errors.clone();
+        let layer = layer(&emitter)
+            .with_error_handler(move |e| reported.lock().unwrap().push(e.to_string()));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler", "x-amzn-trace-id" = "garbage").in_scope(|| {});
+        });
+
+        assert_eq!(emitter.documents()[0]["name"], "handler");
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("invalid trace header"));
+    }
+
+    #[test]
+    fn reports_missing_default_emitter_once() {
+        let errors = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let reported = errors.clone();
+        let layer = XRay::new(Err(io::Error::other("no such host")))
+            .with_sampler(DefaultSampler::new(0, 1.0))
+            .with_error_handler(move |e| reported.lock().unwrap().push(e.to_string()));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("first").in_scope(|| {});
+            tracing::info_span!("second").in_scope(|| {});
+        });
+
+        assert_eq!(
+            *errors.lock().unwrap(),
+            ["failed to emit segment: no such host"]
+        );
+    }
+
+    #[test]
+    fn applies_sampler_to_root_spans_only() {
+        let emitter = MemoryEmitter::new();
+        let layer = XRay::default()
+            .with_emitter(emitter.clone())
+            .with_sampler(DefaultSampler::new(1, 0.0));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                tracing::info_span!("handler").in_scope(|| {
+                    tracing::info_span!("query").in_scope(|| {});
+                });
+            }
+        });
+
+        // the reservoir may refill if the loop straddles a second boundary
+        let documents = emitter.documents();
+        assert!(!documents.is_empty() && documents.len() < 3);
+        assert_eq!(documents[0]["subsegments"][0]["name"], "query");
+    }
+
+    #[test]
+    fn samples_with_local_rules() -> Result<(), XRayError> {
+        let emitter = MemoryEmitter::new();
+        let sampler = r#"{
+            "version": 2,
+            "rules": [{"url_path": "/health", "fixed_target": 0, "rate": 0.0}],
+            "default": {"fixed_target": 0, "rate": 1.0}
+        }"#
+        .parse::<sampling::LocalSampler>()?;
+        let layer = layer(&emitter).with_sampler(sampler);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!(
+                "request",
+                http.method = "GET",
+                http.url = "http://localhost/health"
+            )
+            .in_scope(|| {});
+            tracing::info_span!(
+                "request",
+                http.method = "GET",
+                http.url = "http://localhost/orders"
+            )
+            .in_scope(|| {});
+        });
+
+        assert_eq!(emitter.documents().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn records_http_fields() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(layer(&emitter));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "request",
+                http.method = "POST",
+                http.url = "https://api.example.com/orders",
+                http.user_agent = "curl/8.0",
+                http.client_ip = "203.0.113.7",
+                http.status_code = tracing::field::Empty,
+            );
+            span.record("http.status_code", 429);
+            for status in [200, 503] {
+                tracing::info_span!(parent: &span, "call", http.status_code = status)
+                    .in_scope(|| {});
+            }
+        });
+
+        let document = &emitter.documents()[0];
+        assert_eq!(
+            document["http"],
+            serde_json::json!({
+                "request": {
+                    "method": "POST",
+                    "url": "https://api.example.com/orders",
+                    "user_agent": "curl/8.0",
+                    "client_ip": "203.0.113.7",
+                },
+                "response": { "status": 429 },
+            })
+        );
+        assert_eq!(document["error"], true);
+        assert_eq!(document["throttle"], true);
+        assert!(document.get("fault").is_none());
+
+        let calls = &document["subsegments"];
+        assert!(calls[0].get("error").is_none() && calls[0].get("fault").is_none());
+        assert_eq!(calls[1]["http"]["response"]["status"], 503);
+        assert_eq!(calls[1]["fault"], true);
+    }
+
+    #[test]
+    fn records_fields_as_annotations_and_metadata() {
+        let emitter = MemoryEmitter::new();
+        let layer = layer(&emitter).with_annotation_fields(["tenant"]);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "handler",
+                tenant = "acme",
+                xray.annotation.customer_id = tracing::field::Empty,
+                xray.annotation.retries = 2,
+                xray.metadata.request = ?vec!["a", "b"],
+                ignored = true,
+            );
+            span.record("xray.annotation.customer_id", "c-42");
+            tracing::info_span!(parent: &span, "query", xray.annotation.cached = false)
+                .in_scope(|| {});
+        });
+
+        let document = &emitter.documents()[0];
+        assert_eq!(
+            document["annotations"],
+            serde_json::json!({ "tenant": "acme", "customer_id": "c-42", "retries": 2 })
+        );
+        assert_eq!(document["metadata"]["default"]["request"], "[\"a\", \"b\"]");
+        assert_eq!(document["subsegments"][0]["annotations"]["cached"], false);
+    }
+
+    #[test]
+    fn records_sql_queries() -> Result<(), rusqlite::Error> {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(layer(&emitter));
+
+        let connection = rusqlite::Connection::open_in_memory()?;
+        let statement = "SELECT ?1 + 1";
+        let result = tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").in_scope(|| {
+                let span = tracing::info_span!(
+                    "query",
+                    db.system = "sqlite",
+                    db.url = ":memory:",
+                    db.statement = statement,
+                    db.preparation = "statement",
+                    db.version = rusqlite::version(),
+                );
+                let _guard = span.enter();
+                connection.query_row(statement, [41], |row| row.get::<_, i64>(0))
+            })
+        })?;
+        assert_eq!(result, 42);
+
+        let document = &emitter.documents()[0];
+        let query = &document["subsegments"][0];
+        assert_eq!(query["namespace"], "remote");
+        assert_eq!(
+            query["sql"],
+            serde_json::json!({
+                "url": ":memory:",
+                "sanitized_query": statement,
+                "database_type": "sqlite",
+                "database_version": rusqlite::version(),
+                "preparation": "statement",
+            })
+        );
+        assert!(document.get("sql").is_none());
+        assert!(document.get("namespace").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_sql_fields_on_root_segments() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(layer(&emitter));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("migrate", db.system = "sqlite", db.statement = "VACUUM")
+                .in_scope(|| {});
+        });
+
+        let document = &emitter.documents()[0];
+        assert_eq!(document["name"], "migrate");
+        assert!(document.get("sql").is_none());
+        assert!(document.get("namespace").is_none());
+    }
+
+    #[test]
+    fn records_events_on_enclosing_segment() {
+        let emitter = MemoryEmitter::new();
+        let layer = layer(&emitter).with_event_level(Some(Level::INFO));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").in_scope(|| {
+                tracing::info!(order = 7, "order received");
+                tracing::debug!("too verbose");
+                tracing::error!(error = "connection reset", "query failed");
+            });
+            tracing::info!("outside of any span");
+        });
+
+        let document = &emitter.documents()[0];
+        let events = document["metadata"]["tracing"]["events"]
+            .as_array()
+            .expect("no events recorded");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["message"], "order received");
+        assert_eq!(events[0]["order"], 7);
+        assert_eq!(events[0]["level"], "INFO");
+
+        assert_eq!(document["fault"], true);
+        let exception = &document["cause"]["exceptions"][0];
+        assert_eq!(exception["message"], "connection reset");
+        assert_eq!(exception["id"].as_str().map(str::len), Some(16));
+    }
+
+    #[test]
+    fn caps_events_per_segment() {
+        let emitter = MemoryEmitter::new();
+        let layer = layer(&emitter).with_max_events(2);
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").in_scope(|| {
+                tracing::info!("not recorded by default");
+                for i in 0..5 {
+                    tracing::warn!(i, "retrying");
+                }
+            });
+        });
+
+        let namespace = &emitter.documents()[0]["metadata"]["tracing"];
+        let events = namespace["events"].as_array().expect("no events recorded");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1]["i"], 1);
+        assert_eq!(namespace["dropped"], 3);
     }
 }
@@ -0,0 +1,43 @@
+//! The X-Ray data model: trace headers, identifiers and segment documents
+//!
+//! These types describe the documents sent to X-Ray and the trace context
+//! propagated between services, so that segments can be built, inspected and
+//! tested outside of the [`XRay`](crate::XRay) layer. This module is the only
+//! path the data model is exported at.
+//!
+//! ```
+//! use tracing_xray::model::{Header, SamplingDecision, Segment};
+//!
+//! let header: Header = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1"
+//!     .parse()
+//!     .unwrap();
+//! assert_eq!(header.sampling_decision(), SamplingDecision::Sampled);
+//!
+//! let segment = Segment::begin_from_header("orders", &header);
+//! assert_eq!(segment.trace_id(), header.trace_id());
+//! assert_eq!(segment.parent_id.as_ref(), header.parent_id());
+//! ```
+//!
+//! The document types are non-exhaustive, so that fields X-Ray adds later can
+//! be added here too. They are built from their defaults:
+//!
+//! ```
+//! use tracing_xray::model::{Http, Request, Response, Segment};
+//!
+//! let mut segment = Segment::begin("orders");
+//! segment.http = Some(
+//!     Http::default()
+//!         .with_request(Request::default().with_method("GET").with_url("/orders"))
+//!         .with_response(Response::default().with_status(200)),
+//! );
+//! ```
+
+pub use crate::types::{
+    header::{Header, Lineage, SamplingDecision},
+    ids::{IdError, SegmentId, TraceId},
+    time::Seconds,
+    types::{
+        Annotation, Aws, Cause, Ec2, Ecs, ElasticBeanstalk, Exception, Http, Kind, Namespace,
+        Preparation, Request, Response, Segment, Service, Sql, StackFrame, Tracing, XRaySdk,
+    },
+};
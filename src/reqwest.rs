@@ -0,0 +1,126 @@
+//! [`reqwest_middleware`] adapter recording outbound requests as X-Ray
+//! subsegments
+//!
+//! Available with the `reqwest-middleware` feature.
+
+use crate::{
+    client::{call_span, inject_trace_header, record_status},
+    span_ext::XRaySpanExt,
+};
+use http::Extensions;
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next, Result};
+use tracing::Instrument;
+
+/// Records each request sent by a [`reqwest_middleware::ClientWithMiddleware`]
+/// as a `remote` subsegment and propagates the trace downstream, like the
+/// `XRayClientLayer` of the `tower` feature
+///
+/// ```no_run
+/// use reqwest_middleware::ClientBuilder;
+/// use tracing_xray::reqwest::XRayMiddleware;
+///
+/// let client = ClientBuilder::new(reqwest::Client::new())
+///     .with(XRayMiddleware::new())
+///     .build();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct XRayMiddleware {
+    _private: (),
+}
+
+impl XRayMiddleware {
+    /// Creates a middleware recording requests as remote subsegments
+    pub fn new() -> Self {
+        XRayMiddleware::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for XRayMiddleware {
+    async fn handle(
+        &self,
+        mut request: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        let span = call_span(
+            request.method().as_str(),
+            request.url().as_str(),
+            request.url().host_str(),
+        );
+        inject_trace_header(&span, request.headers_mut());
+        let result = next.run(request, extensions).instrument(span.clone()).await;
+        match &result {
+            Ok(response) => record_status(&span, response.status().as_u16()),
+            Err(_) => span.xray_mark_fault(),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sampling::DefaultSampler, types::header::Header, MemoryEmitter, XRay};
+    use reqwest_middleware::ClientBuilder;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tracing_subscriber::prelude::*;
+
+    #[tokio::test]
+    async fn records_remote_subsegment() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut buf = vec![0; 4096];
+            let len = stream.read(&mut buf).await?;
+            stream
+                .write_all(b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await?;
+            Ok::<_, std::io::Error>(String::from_utf8_lossy(&buf[..len]).into_owned())
+        });
+
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(
+            XRay::default()
+                .with_emitter(emitter.clone())
+                .with_sampler(DefaultSampler::new(0, 1.0)),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(XRayMiddleware::new())
+            .build();
+        let url = format!("http://{}/items/7", address);
+        let status = client
+            .get(&url)
+            .send()
+            .instrument(tracing::info_span!("handler"))
+            .await?
+            .status();
+        assert_eq!(status, 503);
+
+        let request = server.await??.to_ascii_lowercase();
+        let document = &emitter.documents()[0];
+        let call = &document["subsegments"][0];
+        let trace_header = format!(
+            "{}: root={};parent={};sampled=1",
+            Header::NAME,
+            document["trace_id"].as_str().unwrap(),
+            call["id"].as_str().unwrap()
+        )
+        .to_ascii_lowercase();
+        assert!(request.contains(&trace_header), "{}", request);
+        assert_eq!(call["name"], "127.0.0.1");
+        assert_eq!(call["namespace"], "remote");
+        assert_eq!(call["http"]["request"]["url"], url);
+        assert_eq!(call["http"]["request"]["traced"], true);
+        assert_eq!(call["http"]["response"]["status"], 503);
+        assert_eq!(call["fault"], true);
+        Ok(())
+    }
+}
@@ -0,0 +1,766 @@
+use super::{proxy, wildcard::wildcard_match, DefaultSampler, Sampler, SamplingRequest};
+use crate::{
+    daemon::{self, Protocol},
+    error::XRayError,
+    types::{header::SamplingDecision, time::Seconds, types::Bytes},
+    ErrorHandler,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often sampling rules are fetched by default
+const RULES_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often sampling targets are fetched by default
+const TARGETS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Age, in seconds, after which fetched rules are no longer trusted
+const RULES_TTL: f64 = 60.0 * 60.0;
+
+/// Samples traces according to sampling rules managed in X-Ray
+///
+/// Rules are fetched from the X-Ray daemon's sampling proxy every five
+/// minutes by a background thread, which also reports sampling statistics
+/// and fetches the reservoir quotas and rates assigned to this client every
+/// ten seconds. Until rules have been fetched, or when they have not been
+/// refreshed for an hour because the daemon is unreachable, decisions are
+/// made by a fallback sampler, the X-Ray default rule unless configured with
+/// [`CentralizedSampler::with_fallback`].
+///
+/// Errors fetching rules or targets are printed to stderr unless handled
+/// with [`CentralizedSampler::with_error_handler`]. The background thread
+/// stops when the sampler is dropped.
+pub struct CentralizedSampler {
+    state: Arc<State>,
+    fallback: Box<dyn Sampler>,
+    _shutdown: mpsc::Sender<()>,
+}
+
+impl CentralizedSampler {
+    /// Creates a sampler polling the sampling proxy at the daemon address
+    /// found in the environment
+    pub fn from_env() -> Result<Self, XRayError> {
+        Self::new(daemon::address(Protocol::Tcp))
+    }
+
+    /// Creates a sampler polling the sampling proxy at `address`
+    pub fn new<A>(address: A) -> Result<Self, XRayError>
+    where
+        A: ToSocketAddrs,
+    {
+        Self::with_intervals(address, RULES_INTERVAL, TARGETS_INTERVAL)
+    }
+
+    /// Creates a sampler polling the sampling proxy at `address`, fetching
+    /// rules every `rules_interval` and targets every `targets_interval`
+    pub fn with_intervals<A>(
+        address: A,
+        rules_interval: Duration,
+        targets_interval: Duration,
+    ) -> Result<Self, XRayError>
+    where
+        A: ToSocketAddrs,
+    {
+        let address = address
+            .to_socket_addrs()
+            .map_err(|e| XRayError::SamplingProxy(e.to_string()))?
+            .next()
+            .ok_or_else(|| XRayError::SamplingProxy("no sampling proxy address resolved".into()))?;
+        let mut client_id = [0; 12];
+        rand::thread_rng().fill_bytes(&mut client_id);
+        let state = Arc::new(State {
+            address,
+            client_id: format!("{:x}", Bytes(&client_id)),
+            rules: RwLock::default(),
+            error_handler: RwLock::new(Box::new(|e| eprintln!("tracing-xray: {}", e))),
+        });
+        let (shutdown, receiver) = mpsc::channel();
+        let poller = state.clone();
+        thread::Builder::new()
+            .name("xray-sampling".into())
+            .spawn(move || poll(&poller, &receiver, rules_interval, targets_interval))
+            .map_err(|e| XRayError::SamplingProxy(e.to_string()))?;
+        Ok(CentralizedSampler {
+            state,
+            fallback: Box::new(DefaultSampler::default()),
+            _shutdown: shutdown,
+        })
+    }
+
+    /// Makes decisions with `fallback` while no centralized rules are available
+    pub fn with_fallback<T>(mut self, fallback: T) -> Self
+    where
+        T: Sampler,
+    {
+        self.fallback = Box::new(fallback);
+        self
+    }
+
+    /// Handles errors fetching rules or targets from the sampling proxy with
+    /// `handler` rather than printing them to stderr
+    pub fn with_error_handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(XRayError) + Send + Sync + 'static,
+    {
+        *self
+            .state
+            .error_handler
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Box::new(handler);
+        self
+    }
+}
+
+impl Sampler for CentralizedSampler {
+    fn sample(&self, request: &SamplingRequest<'_>) -> SamplingDecision {
+        let now = Seconds::now().0;
+        let rules = self
+            .state
+            .rules
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if rules.is_fresh(now) {
+            if let Some(rule) = rules.rules.iter().find(|rule| rule.matches(request)) {
+                return rule.sample(now);
+            }
+        }
+        drop(rules);
+        self.fallback.sample(request)
+    }
+}
+
+/// State shared between the sampler and its background poller
+struct State {
+    address: SocketAddr,
+    client_id: String,
+    rules: RwLock<Rules>,
+    error_handler: RwLock<ErrorHandler>,
+}
+
+#[derive(Default)]
+struct Rules {
+    /// rules in the order they are evaluated
+    rules: Vec<Rule>,
+    /// when the rules were last fetched, in seconds since the epoch
+    fetched_at: Option<f64>,
+}
+
+impl Rules {
+    fn is_fresh(&self, now: f64) -> bool {
+        self.fetched_at
+            .is_some_and(|fetched_at| now - fetched_at < RULES_TTL)
+    }
+}
+
+struct Rule {
+    name: String,
+    priority: i64,
+    service_name: String,
+    service_type: String,
+    resource_arn: String,
+    host: String,
+    http_method: String,
+    url_path: String,
+    target: Mutex<Target>,
+    statistics: Statistics,
+}
+
+/// The rate and reservoir quota assigned to this client for a rule
+#[derive(Clone)]
+struct Target {
+    fixed_rate: f64,
+    /// samples per second granted to this client, until `expires_at`
+    quota: Option<u64>,
+    expires_at: f64,
+    /// the second currently being counted and the samples taken within it
+    second: u64,
+    taken: u64,
+}
+
+impl Target {
+    fn take(&mut self, now: u64, limit: u64) -> bool {
+        if self.second != now {
+            self.second = now;
+            self.taken = 0;
+        }
+        if self.taken < limit {
+            self.taken += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+struct Statistics {
+    requests: AtomicU64,
+    sampled: AtomicU64,
+    borrowed: AtomicU64,
+}
+
+impl Rule {
+    fn matches(&self, request: &SamplingRequest<'_>) -> bool {
+        let applies = |pattern: &str, value: Option<&str>| {
+            value.is_none_or(|value| wildcard_match(pattern, value))
+        };
+        // rules naming a service type or resource only apply to services
+        // configured with one
+        let describes = |pattern: &str, value: Option<&str>| match value {
+            Some(value) => wildcard_match(pattern, value),
+            None => pattern == "*",
+        };
+        applies(&self.service_name, Some(request.name))
+            && describes(&self.service_type, request.service_type)
+            && describes(&self.resource_arn, request.resource_arn)
+            && applies(&self.host, request.host)
+            && applies(&self.http_method, request.http_method)
+            && applies(&self.url_path, request.url_path)
+    }
+
+    fn sample(&self, now: f64) -> SamplingDecision {
+        self.statistics.requests.fetch_add(1, Ordering::Relaxed);
+        let mut target = self
+            .target
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let second = now.trunc() as u64;
+        let sampled = match target.quota {
+            Some(quota) if now < target.expires_at => target.take(second, quota),
+            // without a current quota, borrow one sample a second until the
+            // next targets are fetched
+            _ => {
+                let borrowed = target.take(second, 1);
+                if borrowed {
+                    self.statistics.borrowed.fetch_add(1, Ordering::Relaxed);
+                }
+                borrowed
+            }
+        } || rand::random::<f64>() < target.fixed_rate;
+
+        if sampled {
+            self.statistics.sampled.fetch_add(1, Ordering::Relaxed);
+            SamplingDecision::Sampled
+        } else {
+            SamplingDecision::NotSampled
+        }
+    }
+
+    /// Takes the statistics collected since the last report
+    fn statistics(&self, client_id: &str, now: f64) -> SamplingStatisticsDocument {
+        SamplingStatisticsDocument {
+            rule_name: self.name.clone(),
+            client_id: client_id.into(),
+            timestamp: now,
+            request_count: self.statistics.requests.swap(0, Ordering::Relaxed),
+            sampled_count: self.statistics.sampled.swap(0, Ordering::Relaxed),
+            borrow_count: self.statistics.borrowed.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+impl From<SamplingRule> for Rule {
+    fn from(rule: SamplingRule) -> Self {
+        Rule {
+            name: rule.rule_name,
+            priority: rule.priority,
+            service_name: rule.service_name,
+            service_type: rule.service_type,
+            resource_arn: rule.resource_arn,
+            host: rule.host,
+            http_method: rule.http_method,
+            url_path: rule.url_path,
+            target: Mutex::new(Target {
+                fixed_rate: rule.fixed_rate,
+                quota: None,
+                expires_at: 0.0,
+                second: 0,
+                taken: 0,
+            }),
+            statistics: Statistics::default(),
+        }
+    }
+}
+
+impl State {
+    fn handle_error(&self, e: XRayError) {
+        let handler = self
+            .error_handler
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        handler(e)
+    }
+
+    /// Fetches all sampling rules, keeping the targets of rules already known
+    fn refresh_rules(&self) -> Result<(), XRayError> {
+        let mut fetched = Vec::new();
+        let mut next_token = None;
+        loop {
+            let response: GetSamplingRulesResponse = proxy::post(
+                self.address,
+                "/GetSamplingRules",
+                &GetSamplingRulesRequest { next_token },
+            )?;
+            fetched.extend(
+                response
+                    .sampling_rule_records
+                    .into_iter()
+                    .filter_map(|record| record.sampling_rule)
+                    .filter(SamplingRule::is_supported)
+                    .map(Rule::from),
+            );
+            next_token = response.next_token.filter(|token| !token.is_empty());
+            if next_token.is_none() {
+                break;
+            }
+        }
+        fetched.sort_by(|a, b| a.priority.cmp(&b.priority).then(a.name.cmp(&b.name)));
+
+        let mut rules = self
+            .rules
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for rule in &mut fetched {
+            if let Some(known) = rules.rules.iter().find(|known| known.name == rule.name) {
+                let known = known
+                    .target
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                let target = rule
+                    .target
+                    .get_mut()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                *target = Target {
+                    fixed_rate: target.fixed_rate,
+                    ..known.clone()
+                };
+            }
+        }
+        rules.rules = fetched;
+        rules.fetched_at = Some(Seconds::now().0);
+        Ok(())
+    }
+
+    /// Reports sampling statistics and applies the targets returned for them
+    ///
+    /// Returns the interval until targets should next be fetched, if one was
+    /// suggested, and whether rules have changed since they were last fetched
+    fn refresh_targets(&self) -> Result<(Option<Duration>, bool), XRayError> {
+        let now = Seconds::now().0;
+        let (documents, fetched_at) = {
+            let rules = self
+                .rules
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let documents = rules
+                .rules
+                .iter()
+                .map(|rule| rule.statistics(&self.client_id, now))
+                .collect::<Vec<_>>();
+            (documents, rules.fetched_at)
+        };
+        if documents.is_empty() {
+            return Ok((None, false));
+        }
+
+        let response: GetSamplingTargetsResponse = proxy::post(
+            self.address,
+            "/SamplingTargets",
+            &GetSamplingTargetsRequest {
+                sampling_statistics_documents: documents,
+            },
+        )?;
+
+        let rules = self
+            .rules
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for document in &response.sampling_target_documents {
+            if let Some(rule) = rules
+                .rules
+                .iter()
+                .find(|rule| rule.name == document.rule_name)
+            {
+                let mut target = rule
+                    .target
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                if let Some(fixed_rate) = document.fixed_rate {
+                    target.fixed_rate = fixed_rate;
+                }
+                if let Some(quota) = document.reservoir_quota {
+                    target.quota = Some(quota);
+                    target.expires_at = document.reservoir_quota_ttl.unwrap_or(f64::MAX);
+                }
+            }
+        }
+        let interval = response
+            .sampling_target_documents
+            .iter()
+            .filter_map(|document| document.interval)
+            .min()
+            .map(Duration::from_secs);
+        let rules_modified = match (response.last_rule_modification, fetched_at) {
+            (Some(modified), Some(fetched_at)) => modified > fetched_at,
+            _ => false,
+        };
+        Ok((interval, rules_modified))
+    }
+}
+
+/// Fetches rules and targets until the sampler is dropped
+fn poll(
+    state: &State,
+    shutdown: &mpsc::Receiver<()>,
+    rules_interval: Duration,
+    targets_interval: Duration,
+) {
+    let (mut next_rules, mut next_targets) = (Instant::now(), Instant::now());
+    loop {
+        if Instant::now() >= next_rules {
+            // failures leave the previous rules in place until they expire
+            if let Err(e) = state.refresh_rules() {
+                state.handle_error(e);
+            }
+            next_rules = Instant::now() + rules_interval;
+        }
+        if Instant::now() >= next_targets {
+            let interval = match state.refresh_targets() {
+                Ok((interval, rules_modified)) => {
+                    if rules_modified {
+                        next_rules = Instant::now();
+                    }
+                    interval.unwrap_or(targets_interval)
+                }
+                Err(e) => {
+                    state.handle_error(e);
+                    targets_interval
+                }
+            };
+            next_targets = Instant::now() + interval;
+        }
+        let wait = next_rules
+            .min(next_targets)
+            .saturating_duration_since(Instant::now());
+        match shutdown.recv_timeout(wait) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            _ => return,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSamplingRulesRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSamplingRulesResponse {
+    #[serde(default)]
+    sampling_rule_records: Vec<SamplingRuleRecord>,
+    next_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SamplingRuleRecord {
+    sampling_rule: Option<SamplingRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SamplingRule {
+    rule_name: String,
+    priority: i64,
+    fixed_rate: f64,
+    service_name: String,
+    service_type: String,
+    host: String,
+    #[serde(rename = "HTTPMethod")]
+    http_method: String,
+    #[serde(rename = "URLPath")]
+    url_path: String,
+    #[serde(rename = "ResourceARN")]
+    resource_arn: String,
+    version: u32,
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+}
+
+impl SamplingRule {
+    /// Whether the rule can be evaluated. Rules matching segment attributes
+    /// are skipped, as spans carry none
+    fn is_supported(&self) -> bool {
+        self.version == 1 && self.attributes.is_empty()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSamplingTargetsRequest {
+    sampling_statistics_documents: Vec<SamplingStatisticsDocument>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SamplingStatisticsDocument {
+    rule_name: String,
+    #[serde(rename = "ClientID")]
+    client_id: String,
+    timestamp: f64,
+    request_count: u64,
+    sampled_count: u64,
+    borrow_count: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSamplingTargetsResponse {
+    #[serde(default)]
+    sampling_target_documents: Vec<SamplingTargetDocument>,
+    last_rule_modification: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SamplingTargetDocument {
+    rule_name: String,
+    fixed_rate: Option<f64>,
+    reservoir_quota: Option<u64>,
+    #[serde(rename = "ReservoirQuotaTTL")]
+    reservoir_quota_ttl: Option<f64>,
+    interval: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    fn rule(name: &str, priority: i64, url_path: &str, fixed_rate: f64) -> Value {
+        json!({
+            "SamplingRule": {
+                "RuleName": name,
+                "RuleARN": format!("arn:aws:xray:us-east-1:123456789012:sampling-rule/{}", name),
+                "ResourceARN": "*",
+                "Priority": priority,
+                "FixedRate": fixed_rate,
+                "ReservoirSize": 1,
+                "ServiceName": "*",
+                "ServiceType": "*",
+                "Host": "*",
+                "HTTPMethod": "*",
+                "URLPath": url_path,
+                "Version": 1,
+                "Attributes": {}
+            }
+        })
+    }
+
+    /// Serves the sampling proxy API, forwarding each request path and body
+    fn daemon(requests: mpsc::Sender<(String, Value)>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                let path = line.split_whitespace().nth(1).unwrap().to_string();
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).unwrap();
+                    if header == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = header.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).unwrap();
+
+                let response = match path.as_str() {
+                    "/GetSamplingRules" => json!({
+                        "SamplingRuleRecords": [
+                            rule("Default", 10000, "*", 0.0),
+                            rule("orders", 1, "/orders*", 1.0)
+                        ]
+                    }),
+                    _ => json!({
+                        "SamplingTargetDocuments": [{
+                            "RuleName": "Default",
+                            "FixedRate": 0.0,
+                            "ReservoirQuota": 0,
+                            "ReservoirQuotaTTL": Seconds::now().0 + 60.0,
+                            "Interval": 10
+                        }],
+                        "LastRuleModification": 0.0,
+                        "UnprocessedStatistics": []
+                    }),
+                }
+                .to_string();
+                write!(
+                    stream.get_mut(),
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+                let _ = requests.send((path, serde_json::from_slice(&body).unwrap()));
+            }
+        });
+        address
+    }
+
+    fn request(path: &str) -> SamplingRequest<'_> {
+        SamplingRequest {
+            url_path: Some(path),
+            ..SamplingRequest::new("handler")
+        }
+    }
+
+    #[test]
+    fn samples_with_rules_and_targets_from_daemon() {
+        let (sender, requests) = mpsc::channel();
+        let sampler = CentralizedSampler::new(daemon(sender))
+            .unwrap()
+            .with_fallback(DefaultSampler::new(0, 1.0));
+
+        let (path, _) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(path, "/GetSamplingRules");
+        let (path, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(path, "/SamplingTargets");
+        let names = body["SamplingStatisticsDocuments"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|document| document["RuleName"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["orders", "Default"]);
+
+        // wait for the targets to be applied
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            let rules = sampler.state.rules.read().unwrap();
+            if rules.rules[1].target.lock().unwrap().quota.is_some() {
+                break;
+            }
+            drop(rules);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(
+            sampler.sample(&request("/orders/1")),
+            SamplingDecision::Sampled
+        );
+        assert_eq!(
+            sampler.sample(&request("/health")),
+            SamplingDecision::NotSampled
+        );
+
+        let now = Seconds::now().0;
+        let rules = sampler.state.rules.read().unwrap();
+        let orders = rules.rules[0].statistics("client", now);
+        assert_eq!((orders.request_count, orders.sampled_count), (1, 1));
+        let default = rules.rules[1].statistics("client", now);
+        assert_eq!(
+            (
+                default.request_count,
+                default.sampled_count,
+                default.borrow_count
+            ),
+            (1, 0, 0)
+        );
+    }
+
+    fn parse_rule(rule: Value) -> SamplingRule {
+        serde_json::from_value(rule["SamplingRule"].clone()).unwrap()
+    }
+
+    #[test]
+    fn matches_service_type_and_resource_arn() {
+        let mut ec2 = rule("ec2", 1, "*", 1.0);
+        ec2["SamplingRule"]["ServiceType"] = json!("AWS::EC2::Instance");
+        let ec2 = Rule::from(parse_rule(ec2));
+        let mut orders = rule("orders", 1, "*", 1.0);
+        orders["SamplingRule"]["ResourceARN"] = json!("arn:aws:ecs:*:*:service/orders");
+        let orders = Rule::from(parse_rule(orders));
+
+        let mut request = SamplingRequest::new("handler");
+        assert!(!ec2.matches(&request));
+        assert!(!orders.matches(&request));
+
+        request.service_type = Some("AWS::ECS::Container");
+        request.resource_arn = Some("arn:aws:ecs:us-east-1:123456789012:service/orders");
+        assert!(!ec2.matches(&request));
+        assert!(orders.matches(&request));
+
+        request.service_type = Some("AWS::EC2::Instance");
+        request.resource_arn = Some("arn:aws:ecs:us-east-1:123456789012:service/billing");
+        assert!(ec2.matches(&request));
+        assert!(!orders.matches(&request));
+
+        assert!(Rule::from(parse_rule(rule("any", 1, "*", 1.0))).matches(&request));
+    }
+
+    #[test]
+    fn skips_rules_with_attributes() {
+        assert!(parse_rule(rule("plain", 1, "*", 1.0)).is_supported());
+        let mut tenant = rule("tenant", 1, "*", 1.0);
+        tenant["SamplingRule"]["Attributes"] = json!({ "tenant": "acme" });
+        assert!(!parse_rule(tenant).is_supported());
+        let mut future = rule("future", 1, "*", 1.0);
+        future["SamplingRule"]["Version"] = json!(2);
+        assert!(!parse_rule(future).is_supported());
+    }
+
+    #[test]
+    fn reports_unresolvable_address_as_proxy_error() {
+        match CentralizedSampler::new("no port") {
+            Err(XRayError::SamplingProxy(_)) => {}
+            other => panic!("expected a sampling proxy error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn falls_back_when_daemon_is_unreachable() {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (sender, errors) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let interval = Duration::from_millis(50);
+        let sampler = CentralizedSampler::with_intervals(address, interval, interval)
+            .unwrap()
+            .with_fallback(DefaultSampler::new(0, 1.0))
+            .with_error_handler(move |e| {
+                let _ = sender.lock().unwrap().send(e.to_string());
+            });
+        assert_eq!(
+            sampler.sample(&request("/orders")),
+            SamplingDecision::Sampled
+        );
+        assert!(errors.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+}
@@ -0,0 +1,224 @@
+use super::{sample, wildcard::wildcard_match, Reservoir, Sampler, SamplingRequest};
+use crate::{
+    error::XRayError,
+    types::{header::SamplingDecision, time::Seconds},
+};
+use serde::Deserialize;
+use std::{fs, path::Path, str::FromStr};
+
+/// Samples traces according to a version 2 local sampling rules document
+///
+/// The document uses the same format as the AWS SDKs:
+///
+/// ```json
+/// {
+///   "version": 2,
+///   "rules": [
+///     {
+///       "description": "Player moves.",
+///       "host": "*",
+///       "http_method": "*",
+///       "url_path": "/api/move/*",
+///       "fixed_target": 0,
+///       "rate": 0.05
+///     }
+///   ],
+///   "default": {
+///     "fixed_target": 1,
+///     "rate": 0.1
+///   }
+/// }
+/// ```
+///
+/// Rules are evaluated in order and the first rule matching the request is
+/// applied, falling back to the default rule. `service_name` is matched
+/// against the span name, `host`, `http_method` and `url_path` against the
+/// span's `http.method` and `http.url` fields. Omitted properties, and
+/// properties missing from the request, match anything.
+#[derive(Debug)]
+pub struct LocalSampler {
+    rules: Vec<Rule>,
+    default: Rule,
+}
+
+#[derive(Debug)]
+struct Rule {
+    service_name: String,
+    host: String,
+    http_method: String,
+    url_path: String,
+    reservoir: Reservoir,
+    rate: f64,
+}
+
+impl Rule {
+    fn matches(&self, request: &SamplingRequest<'_>) -> bool {
+        let applies = |pattern: &str, value: Option<&str>| {
+            value.is_none_or(|value| wildcard_match(pattern, value))
+        };
+        applies(&self.service_name, Some(request.name))
+            && applies(&self.host, request.host)
+            && applies(&self.http_method, request.http_method)
+            && applies(&self.url_path, request.url_path)
+    }
+}
+
+#[derive(Deserialize)]
+struct Document {
+    version: u8,
+    #[serde(default)]
+    rules: Vec<RuleDocument>,
+    default: RuleDocument,
+}
+
+#[derive(Deserialize)]
+struct RuleDocument {
+    service_name: Option<String>,
+    host: Option<String>,
+    http_method: Option<String>,
+    url_path: Option<String>,
+    fixed_target: u64,
+    rate: f64,
+}
+
+impl TryFrom<RuleDocument> for Rule {
+    type Error = XRayError;
+
+    fn try_from(doc: RuleDocument) -> Result<Self, Self::Error> {
+        if !(0.0..=1.0).contains(&doc.rate) {
+            return Err(XRayError::InvalidSamplingRules(format!(
+                "rate `{}` must be between 0 and 1",
+                doc.rate
+            )));
+        }
+        let pattern = |value: Option<String>| value.unwrap_or_else(|| "*".into());
+        Ok(Rule {
+            service_name: pattern(doc.service_name),
+            host: pattern(doc.host),
+            http_method: pattern(doc.http_method),
+            url_path: pattern(doc.url_path),
+            reservoir: Reservoir::new(doc.fixed_target),
+            rate: doc.rate,
+        })
+    }
+}
+
+impl LocalSampler {
+    /// Loads a sampling rules document from the file at `path`
+    pub fn from_file<P>(path: P) -> Result<Self, XRayError>
+    where
+        P: AsRef<Path>,
+    {
+        fs::read_to_string(path)
+            .map_err(|e| XRayError::InvalidSamplingRules(e.to_string()))?
+            .parse()
+    }
+
+    fn rule(&self, request: &SamplingRequest<'_>) -> &Rule {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(request))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl FromStr for LocalSampler {
+    type Err = XRayError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let doc: Document =
+            serde_json::from_str(s).map_err(|e| XRayError::InvalidSamplingRules(e.to_string()))?;
+        if doc.version != 2 {
+            return Err(XRayError::InvalidSamplingRules(format!(
+                "unsupported version `{}`, expected 2",
+                doc.version
+            )));
+        }
+        Ok(LocalSampler {
+            rules: doc
+                .rules
+                .into_iter()
+                .map(Rule::try_from)
+                .collect::<Result<_, _>>()?,
+            default: doc.default.try_into()?,
+        })
+    }
+}
+
+impl Sampler for LocalSampler {
+    fn sample(&self, request: &SamplingRequest<'_>) -> SamplingDecision {
+        let rule = self.rule(request);
+        sample(&rule.reservoir, rule.rate, Seconds::now().trunc())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"{
+        "version": 2,
+        "rules": [
+            {
+                "description": "health checks",
+                "url_path": "/health",
+                "fixed_target": 0,
+                "rate": 0.0
+            },
+            {
+                "description": "orders",
+                "host": "*.example.com",
+                "http_method": "POST",
+                "url_path": "/orders/*",
+                "fixed_target": 0,
+                "rate": 1.0
+            }
+        ],
+        "default": {
+            "fixed_target": 0,
+            "rate": 0.0
+        }
+    }"#;
+
+    fn request<'a>(method: &'a str, host: &'a str, path: &'a str) -> SamplingRequest<'a> {
+        SamplingRequest {
+            http_method: Some(method),
+            host: Some(host),
+            url_path: Some(path),
+            ..SamplingRequest::new("handler")
+        }
+    }
+
+    #[test]
+    fn applies_first_matching_rule() -> Result<(), XRayError> {
+        let sampler = RULES.parse::<LocalSampler>()?;
+        assert_eq!(
+            sampler.sample(&request("post", "api.example.com", "/orders/1")),
+            SamplingDecision::Sampled
+        );
+        assert_eq!(
+            sampler.sample(&request("GET", "api.example.com", "/orders/1")),
+            SamplingDecision::NotSampled
+        );
+        assert_eq!(
+            sampler.sample(&request("POST", "api.example.com", "/health")),
+            SamplingDecision::NotSampled
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        assert!(
+            r#"{"version": 1, "default": {"fixed_target": 1, "rate": 0.1}}"#
+                .parse::<LocalSampler>()
+                .is_err()
+        );
+        assert!(
+            r#"{"version": 2, "default": {"fixed_target": 1, "rate": 1.5}}"#
+                .parse::<LocalSampler>()
+                .is_err()
+        );
+        assert!(r#"{"version": 2, "rules": []}"#.parse::<LocalSampler>().is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Sampling decisions for new traces
+//!
+//! A [`Sampler`] decides whether a root segment, and with it the whole trace,
+//! is recorded. Subsegments always follow the decision made for their root.
+//! [`DefaultSampler`] implements the X-Ray default rule: the first request each
+//! second is sampled, plus a fixed percentage of any additional requests.
+//! [`LocalSampler`] applies per-route rules from a local sampling rules document
+//! and [`CentralizedSampler`] applies the rules managed in X-Ray, fetched
+//! through the daemon.
+
+mod centralized;
+mod local;
+mod proxy;
+mod reservoir;
+mod wildcard;
+
+pub use centralized::CentralizedSampler;
+pub use local::LocalSampler;
+pub(crate) use reservoir::Reservoir;
+
+use crate::types::{header::SamplingDecision, time::Seconds, types::Segment};
+
+/// Describes the root span a sampling decision is being made for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingRequest<'a> {
+    /// The name of the span, which becomes the name of the segment
+    pub name: &'a str,
+    /// The host of the span's `http.url` field
+    pub host: Option<&'a str>,
+    /// The span's `http.method` field
+    pub http_method: Option<&'a str>,
+    /// The path of the span's `http.url` field
+    pub url_path: Option<&'a str>,
+    /// The type of AWS resource running the application, such as
+    /// `AWS::EC2::Instance`
+    pub service_type: Option<&'a str>,
+    /// The ARN of the AWS resource running the application
+    pub resource_arn: Option<&'a str>,
+}
+
+impl<'a> SamplingRequest<'a> {
+    /// Creates a request for a span named `name`
+    pub fn new(name: &'a str) -> Self {
+        SamplingRequest {
+            name,
+            host: None,
+            http_method: None,
+            url_path: None,
+            service_type: None,
+            resource_arn: None,
+        }
+    }
+
+    /// Creates a request for a segment, including the HTTP request it handles
+    pub(crate) fn for_segment(segment: &'a Segment) -> Self {
+        let request = segment.http.as_ref().and_then(|http| http.request.as_ref());
+        let (host, url_path) = match request.and_then(|request| request.url.as_deref()) {
+            Some(url) => split_url(url),
+            None => (None, None),
+        };
+        SamplingRequest {
+            name: &segment.name,
+            host,
+            http_method: request.and_then(|request| request.method.as_deref()),
+            url_path,
+            service_type: segment.origin.as_deref(),
+            resource_arn: segment.resource_arn.as_deref(),
+        }
+    }
+}
+
+/// Splits a URL, or a bare path, into its host and path
+fn split_url(url: &str) -> (Option<&str>, Option<&str>) {
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    match url.split_once("://") {
+        Some((_, rest)) => match rest.find('/') {
+            Some(pos) => (Some(&rest[..pos]), Some(&rest[pos..])),
+            None => (Some(rest), Some("/")),
+        },
+        None => (None, Some(url)),
+    }
+}
+
+/// Decides which traces are recorded
+pub trait Sampler: Send + Sync + 'static {
+    /// Returns either [`SamplingDecision::Sampled`] or
+    /// [`SamplingDecision::NotSampled`] for a new trace
+    fn sample(&self, request: &SamplingRequest<'_>) -> SamplingDecision;
+}
+
+/// Samples a fixed number of traces each second and a fixed percentage beyond that
+///
+/// The default instance matches the X-Ray default sampling rule: a reservoir of
+/// one trace per second plus 5% of additional traces.
+#[derive(Debug)]
+pub struct DefaultSampler {
+    reservoir: Reservoir,
+    rate: f64,
+}
+
+impl DefaultSampler {
+    /// Creates a sampler which samples `fixed_target` traces each second and
+    /// `rate` (between 0.0 and 1.0) of the traces beyond that
+    pub fn new(fixed_target: u64, rate: f64) -> Self {
+        DefaultSampler {
+            reservoir: Reservoir::new(fixed_target),
+            rate: rate.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl Default for DefaultSampler {
+    fn default() -> Self {
+        DefaultSampler::new(1, 0.05)
+    }
+}
+
+impl Sampler for DefaultSampler {
+    fn sample(&self, _: &SamplingRequest<'_>) -> SamplingDecision {
+        sample(&self.reservoir, self.rate, Seconds::now().trunc())
+    }
+}
+
+/// Samples from the reservoir first, falling back to the fixed rate once the
+/// reservoir for the current second is exhausted
+pub(crate) fn sample(reservoir: &Reservoir, rate: f64, now: u64) -> SamplingDecision {
+    if reservoir.take(now) || rand::random::<f64>() < rate {
+        SamplingDecision::Sampled
+    } else {
+        SamplingDecision::NotSampled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_reservoir_then_fixed_rate() {
+        let reservoir = Reservoir::new(1);
+        assert_eq!(sample(&reservoir, 0.0, 10), SamplingDecision::Sampled);
+        assert_eq!(sample(&reservoir, 0.0, 10), SamplingDecision::NotSampled);
+        assert_eq!(sample(&reservoir, 1.0, 10), SamplingDecision::Sampled);
+        assert_eq!(sample(&reservoir, 0.0, 11), SamplingDecision::Sampled);
+    }
+
+    #[test]
+    fn splits_urls() {
+        assert_eq!(
+            split_url("https://api.example.com/orders/1?page=2"),
+            (Some("api.example.com"), Some("/orders/1"))
+        );
+        assert_eq!(
+            split_url("http://api.example.com"),
+            (Some("api.example.com"), Some("/"))
+        );
+        assert_eq!(split_url("/orders#top"), (None, Some("/orders")));
+    }
+
+    #[test]
+    fn default_sampler_samples_first_request() {
+        let sampler = DefaultSampler::new(1, 0.0);
+        let request = SamplingRequest::new("handler");
+        assert_eq!(sampler.sample(&request), SamplingDecision::Sampled);
+    }
+}
@@ -0,0 +1,128 @@
+//! Minimal HTTP/1.1 client for the daemon's sampling proxy
+
+use crate::error::XRayError;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+/// Time allowed to connect to, write to and read from the daemon
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// POSTs `body` as JSON to `path` on the sampling proxy and decodes the JSON response
+pub(crate) fn post<B, R>(address: SocketAddr, path: &str, body: &B) -> Result<R, XRayError>
+where
+    B: Serialize,
+    R: DeserializeOwned,
+{
+    let body = serde_json::to_vec(body)?;
+    let response = exchange(address, path, &body).map_err(proxy_error)?;
+    let body = parse_response(&response)?;
+    serde_json::from_slice(&body).map_err(|e| proxy_error(format!("invalid response: {}", e)))
+}
+
+fn exchange(address: SocketAddr, path: &str, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        address,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
+
+/// Checks the status of a raw HTTP response and returns its decoded body
+fn parse_response(response: &[u8]) -> Result<Vec<u8>, XRayError> {
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| proxy_error("incomplete response"))?;
+    let head = std::str::from_utf8(&response[..split])
+        .map_err(|_| proxy_error("response headers are not utf8"))?;
+    let body = &response[split + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| proxy_error("malformed status line"))?;
+    if !(200..300).contains(&status) {
+        return Err(proxy_error(format!("unexpected status {}", status)));
+    }
+
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    if chunked {
+        decode_chunked(body)
+    } else {
+        Ok(body.to_vec())
+    }
+}
+
+fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>, XRayError> {
+    let mut decoded = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(|| proxy_error("incomplete chunk"))?;
+        let size = std::str::from_utf8(&body[..line_end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .ok_or_else(|| proxy_error("malformed chunk size"))?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        let chunk = body
+            .get(..size)
+            .ok_or_else(|| proxy_error("incomplete chunk"))?;
+        decoded.extend_from_slice(chunk);
+        body = body.get(size + 2..).unwrap_or_default();
+    }
+}
+
+fn proxy_error<E>(e: E) -> XRayError
+where
+    E: ToString,
+{
+    XRayError::SamplingProxy(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_content_length_response() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(parse_response(response).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn parses_chunked_response() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n{\"a\r\n4\r\n\":1}\r\n0\r\n\r\n";
+        assert_eq!(parse_response(response).unwrap(), b"{\"a\":1}");
+    }
+
+    #[test]
+    fn rejects_error_status() {
+        let response = b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n";
+        assert!(parse_response(response).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::Mutex;
+
+/// Hands out a fixed number of samples for each second of wall clock time
+#[derive(Debug)]
+pub(crate) struct Reservoir {
+    per_second: u64,
+    /// the second currently being counted and the samples taken within it
+    state: Mutex<(u64, u64)>,
+}
+
+impl Reservoir {
+    pub(crate) fn new(per_second: u64) -> Self {
+        Reservoir {
+            per_second,
+            state: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Takes a sample for the second `now`, returning false once the
+    /// reservoir for that second is exhausted
+    pub(crate) fn take(&self, now: u64) -> bool {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (second, taken) = &mut *state;
+        if *second != now {
+            *second = now;
+            *taken = 0;
+        }
+        if *taken < self.per_second {
+            *taken += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,64 @@
+/// Matches `text` against a sampling rule `pattern`, ignoring case
+///
+/// As in the AWS SDKs, `*` matches any run of characters, including none, and
+/// `?` matches exactly one character.
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` seen in the pattern, and the text position it
+    // was matched from, to backtrack to on a mismatch
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || same_char(c, text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn same_char(a: char, b: char) -> bool {
+    a == b || a.to_lowercase().eq(b.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wildcard_match;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("", ""));
+        assert!(!wildcard_match("", "a"));
+        assert!(wildcard_match("/api/*", "/api/users/1"));
+        assert!(wildcard_match("/api/*", "/api/"));
+        assert!(!wildcard_match("/api/*", "/health"));
+        assert!(wildcard_match("*.example.com", "www.example.com"));
+        assert!(wildcard_match("GET", "get"));
+        assert!(wildcard_match("/users/?", "/users/1"));
+        assert!(!wildcard_match("/users/?", "/users/10"));
+        assert!(wildcard_match("a*b*c", "aXXbYYbc"));
+        assert!(!wildcard_match("a*b*c", "aXXbYYb"));
+        assert!(wildcard_match("**", "x"));
+    }
+}
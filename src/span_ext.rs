@@ -0,0 +1,424 @@
+//! Access to the X-Ray state of a [`tracing::Span`] from application code
+
+use crate::types::{
+    header::{Header, SamplingDecision},
+    types::{Annotation, Exception, Segment},
+};
+use serde_json::Value;
+use std::{backtrace::Backtrace, error::Error};
+use tracing::{span::Id, Dispatch, Span};
+
+/// Metadata namespace used by [`XRaySpanExt::xray_metadata`]
+pub(crate) const DEFAULT_NAMESPACE: &str = "default";
+
+/// Runs a closure against the segment recorded for a span
+///
+/// The [`XRay`](crate::XRay) layer exposes this through
+/// [`Dispatch::downcast_ref`] so that spans can reach their segment without
+/// knowing the concrete type of the subscriber the layer is attached to.
+#[derive(Clone, Copy)]
+pub(crate) struct WithSegment(
+    #[allow(clippy::type_complexity)]
+    pub(crate)  fn(&Dispatch, &Id, &mut dyn FnMut(&mut Segment, SamplingDecision)),
+);
+
+impl WithSegment {
+    pub(crate) fn with_segment(
+        &self,
+        dispatch: &Dispatch,
+        id: &Id,
+        f: &mut dyn FnMut(&mut Segment, SamplingDecision),
+    ) {
+        (self.0)(dispatch, id, f)
+    }
+}
+
+/// Extends [`tracing::Span`] with access to the X-Ray segment recorded for it
+pub trait XRaySpanExt {
+    /// Returns the header to send upstream in the `X-Amzn-Trace-Id` response
+    /// header, carrying the trace id and the resolved sampling decision
+    ///
+    /// When a request arrives with `Sampled=?` the layer makes its own sampling
+    /// decision, which the X-Ray protocol expects to be reported back to the
+    /// caller in the response. Returns `None` if the span is not recorded by
+    /// an [`XRay`](crate::XRay) layer.
+    fn xray_response_header(&self) -> Option<Header>;
+
+    /// Returns the header to send downstream in the `X-Amzn-Trace-Id` request
+    /// header of an outgoing call made within this span
+    ///
+    /// The header carries the trace id, the id of the span's (sub)segment as
+    /// the parent and the trace's sampling decision, so that the downstream
+    /// service continues the same trace. A
+    /// [`Lineage`](crate::model::Lineage) received upstream is propagated
+    /// with its counter incremented. Returns `None` if the span is not
+    /// recorded by an [`XRay`](crate::XRay) layer.
+    ///
+    /// ```no_run
+    /// use tracing_xray::{model::Header, XRaySpanExt};
+    ///
+    /// let mut headers = Vec::new();
+    /// if let Some(header) = tracing::Span::current().xray_trace_header() {
+    ///     headers.push((Header::NAME, header.to_string()));
+    /// }
+    /// ```
+    fn xray_trace_header(&self) -> Option<Header>;
+
+    /// Runs `f` against the span's segment, allowing any of its fields to be
+    /// set. Does nothing if the span is not recorded by an
+    /// [`XRay`](crate::XRay) layer.
+    ///
+    /// The segment is detached from the span while `f` runs, so events
+    /// recorded within `f` are not added to it.
+    fn xray_update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Segment);
+
+    /// Adds an annotation to the span's segment, which X-Ray indexes for use
+    /// with filter expressions
+    fn xray_annotate<K, V>(&self, key: K, value: V)
+    where
+        K: AsRef<str>,
+        V: Into<Annotation>,
+    {
+        self.xray_update(|segment| {
+            segment.annotate(key, value);
+        })
+    }
+
+    /// Adds metadata to the `default` namespace of the span's segment
+    fn xray_metadata<K, V>(&self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<Value>,
+    {
+        self.xray_update(|segment| {
+            segment.insert_metadata(DEFAULT_NAMESPACE, key, value);
+        })
+    }
+
+    /// Records the user who sent the request
+    fn xray_set_user<U>(&self, user: U)
+    where
+        U: Into<String>,
+    {
+        self.xray_update(|segment| segment.user = Some(user.into()))
+    }
+
+    /// Records that a server error occurred
+    fn xray_mark_fault(&self) {
+        self.xray_update(|segment| segment.fault = true)
+    }
+
+    /// Records `error` and its chain of sources as exceptions and marks the
+    /// segment as faulted
+    fn xray_record_error(&self, error: &(dyn Error + 'static)) {
+        self.xray_update(|segment| {
+            segment.fault = true;
+            segment.add_exceptions(Exception::chain(error));
+        })
+    }
+
+    /// Records `error` and its chain of sources as exceptions, with the frames
+    /// of `backtrace` as the stack of `error`, and marks the segment as faulted
+    fn xray_record_error_with_backtrace(
+        &self,
+        error: &(dyn Error + 'static),
+        backtrace: &Backtrace,
+    ) {
+        self.xray_update(|segment| {
+            let mut exceptions = Exception::chain(error);
+            if let Some(exception) = exceptions.first_mut() {
+                exception.set_backtrace(backtrace);
+            }
+            segment.fault = true;
+            segment.add_exceptions(exceptions);
+        })
+    }
+
+    /// Records that a client error occurred
+    fn xray_mark_error(&self) {
+        self.xray_update(|segment| segment.error = true)
+    }
+
+    /// Records that the request was throttled, which is also a client error
+    fn xray_mark_throttle(&self) {
+        self.xray_update(|segment| {
+            segment.throttle = true;
+            segment.error = true;
+        })
+    }
+}
+
+impl XRaySpanExt for Span {
+    fn xray_response_header(&self) -> Option<Header> {
+        let mut header = None;
+        with_segment(self, &mut |segment, decision| {
+            let mut response = Header::new(segment.trace_id);
+            response.with_sampling_decision(decision);
+            header = Some(response);
+        });
+        header
+    }
+
+    fn xray_trace_header(&self) -> Option<Header> {
+        let mut header = None;
+        with_segment(self, &mut |segment, decision| {
+            let mut downstream = Header::new(segment.trace_id);
+            downstream
+                .with_parent_id(segment.id)
+                .with_sampling_decision(match decision {
+                    SamplingDecision::NotSampled => SamplingDecision::NotSampled,
+                    _ => SamplingDecision::Sampled,
+                });
+            if let Some(lineage) = segment.lineage {
+                downstream.with_lineage(lineage.next());
+            }
+            header = Some(downstream);
+        });
+        header
+    }
+
+    fn xray_update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Segment),
+    {
+        let mut f = Some(f);
+        with_segment(self, &mut |segment, _| {
+            if let Some(f) = f.take() {
+                f(segment)
+            }
+        });
+    }
+}
+
+/// Runs `f` against the segment of `span`, if it has one
+fn with_segment(span: &Span, f: &mut dyn FnMut(&mut Segment, SamplingDecision)) {
+    span.with_subscriber(|(id, dispatch)| {
+        if let Some(get_segment) = dispatch.downcast_ref::<WithSegment>() {
+            get_segment.with_segment(dispatch, id, f);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sampling::DefaultSampler, MemoryEmitter, XRay};
+    use tracing_subscriber::prelude::*;
+
+    fn response_header(sampler: DefaultSampler, upstream: &str) -> Option<String> {
+        let layer = XRay::default()
+            .with_emitter(MemoryEmitter::new())
+            .with_sampler(sampler);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("handler", "x-amzn-trace-id" = upstream);
+            let _guard = span.enter();
+            tracing::info_span!("inner")
+                .xray_response_header()
+                .map(|header| header.to_string())
+        })
+    }
+
+    #[test]
+    fn resolves_requested_sampling_decision() {
+        let upstream = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=?";
+        assert_eq!(
+            response_header(DefaultSampler::new(0, 1.0), upstream).as_deref(),
+            Some("Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1")
+        );
+        assert_eq!(
+            response_header(DefaultSampler::new(0, 0.0), upstream).as_deref(),
+            Some("Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=0")
+        );
+    }
+
+    #[test]
+    fn keeps_upstream_sampling_decision() {
+        let upstream = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1";
+        assert_eq!(
+            response_header(DefaultSampler::new(0, 0.0), upstream).as_deref(),
+            Some("Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1")
+        );
+    }
+
+    #[test]
+    fn propagates_current_subsegment_downstream() {
+        let emitter = MemoryEmitter::new();
+        let layer = XRay::default()
+            .with_emitter(emitter.clone())
+            .with_sampler(DefaultSampler::new(0, 1.0));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        let header = tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").in_scope(|| {
+                tracing::info_span!("call").in_scope(|| Span::current().xray_trace_header())
+            })
+        })
+        .expect("span has no trace header");
+
+        let root = &emitter.documents()[0];
+        let call = &root["subsegments"][0];
+        assert_eq!(
+            header.to_string(),
+            format!(
+                "Root={};Parent={};Sampled=1",
+                root["trace_id"].as_str().unwrap(),
+                call["id"].as_str().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn increments_lineage_downstream() {
+        let layer = XRay::default().with_emitter(MemoryEmitter::new());
+        let subscriber = tracing_subscriber::registry().with(layer);
+        let upstream = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1;Lineage=a87bd80c:2";
+
+        let header = tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler", "x-amzn-trace-id" = upstream).in_scope(|| {
+                tracing::info_span!("call").in_scope(|| Span::current().xray_trace_header())
+            })
+        })
+        .expect("span has no trace header");
+        assert_eq!(
+            header.lineage(),
+            Some(crate::model::Lineage::new(0xa87bd80c, 3))
+        );
+    }
+
+    #[test]
+    fn propagates_unsampled_decision_downstream() {
+        let layer = XRay::default()
+            .with_emitter(MemoryEmitter::new())
+            .with_sampler(DefaultSampler::new(0, 0.0));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        let header = tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").xray_trace_header()
+        })
+        .expect("span has no trace header");
+        assert_eq!(header.sampling_decision, SamplingDecision::NotSampled);
+    }
+
+    #[test]
+    fn updates_segment_fields() {
+        let emitter = MemoryEmitter::new();
+        let layer = XRay::default()
+            .with_emitter(emitter.clone())
+            .with_sampler(DefaultSampler::new(0, 1.0));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("handler");
+            span.xray_annotate("customer_id", "c-42");
+            span.xray_annotate("tenant.region", 3usize);
+            span.xray_metadata("payload", serde_json::json!({ "items": 2 }));
+            span.xray_set_user("alice");
+            span.xray_mark_fault();
+            span.xray_update(|segment| segment.origin = Some("AWS::EC2::Instance".into()));
+        });
+
+        let document = &emitter.documents()[0];
+        assert_eq!(document["annotations"]["customer_id"], "c-42");
+        assert_eq!(document["annotations"]["tenant_region"], 3);
+        assert_eq!(document["metadata"]["default"]["payload"]["items"], 2);
+        assert_eq!(document["user"], "alice");
+        assert_eq!(document["fault"], true);
+        assert_eq!(document["origin"], "AWS::EC2::Instance");
+    }
+
+    #[test]
+    fn logs_within_update() {
+        let emitter = MemoryEmitter::new();
+        let layer = XRay::default()
+            .with_emitter(emitter.clone())
+            .with_sampler(DefaultSampler::new(0, 1.0));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("handler");
+            let _guard = span.enter();
+            span.xray_update(|segment| {
+                tracing::warn!("updating");
+                segment.user = Some("alice".into());
+            });
+            tracing::warn!("updated");
+        });
+
+        let document = &emitter.documents()[0];
+        assert_eq!(document["user"], "alice");
+        let events = &document["metadata"]["tracing"]["events"];
+        assert_eq!(events.as_array().map(Vec::len), Some(1));
+        assert_eq!(events[0]["message"], "updated");
+    }
+
+    #[test]
+    fn records_error_chain() {
+        let emitter = MemoryEmitter::new();
+        let layer = XRay::default()
+            .with_emitter(emitter.clone())
+            .with_sampler(DefaultSampler::new(0, 1.0));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        #[derive(Debug)]
+        struct RequestFailed(std::fmt::Error);
+
+        impl std::fmt::Display for RequestFailed {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("request failed")
+            }
+        }
+
+        impl Error for RequestFailed {
+            fn source(&self) -> Option<&(dyn Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let error = RequestFailed(std::fmt::Error);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler").xray_record_error(&error);
+        });
+
+        let document = &emitter.documents()[0];
+        assert_eq!(document["fault"], true);
+        let exceptions = document["cause"]["exceptions"].as_array().unwrap();
+        assert_eq!(exceptions.len(), 2);
+        assert_eq!(exceptions[0]["message"], "request failed");
+        assert_eq!(exceptions[0]["cause"], exceptions[1]["id"]);
+        assert_eq!(
+            exceptions[1]["message"],
+            "an error occurred when formatting an argument"
+        );
+        assert!(document["cause"]["working_directory"].is_string());
+    }
+
+    #[test]
+    fn records_error_with_backtrace() {
+        let emitter = MemoryEmitter::new();
+        let layer = XRay::default()
+            .with_emitter(emitter.clone())
+            .with_sampler(DefaultSampler::new(0, 1.0));
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        let backtrace = Backtrace::force_capture();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("handler")
+                .xray_record_error_with_backtrace(&std::fmt::Error, &backtrace);
+        });
+
+        let document = &emitter.documents()[0];
+        assert_eq!(document["fault"], true);
+        let exception = &document["cause"]["exceptions"][0];
+        assert!(!exception["stack"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn no_header_without_layer() {
+        let span = tracing::info_span!("handler");
+        assert!(span.xray_response_header().is_none());
+        assert!(span.xray_trace_header().is_none());
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{
+    client::{call_span, inject_trace_header, record_status},
+    span_ext::XRaySpanExt,
+};
+use http::{Request, Response};
+use pin_project_lite::pin_project;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tower::{Layer, Service};
+use tracing::Span;
+
+/// Wraps HTTP clients with [`XRayClientService`]
+///
+/// Each request is sent within a `call` span which the [`XRay`](crate::XRay)
+/// tracing layer records as a `remote` subsegment of the current segment,
+/// named after the host called. The trace is propagated downstream in the
+/// `X-Amzn-Trace-Id` request header.
+#[derive(Clone, Debug, Default)]
+pub struct XRayClientLayer {
+    _private: (),
+}
+
+impl XRayClientLayer {
+    /// Creates a layer recording outbound requests as remote subsegments
+    pub fn new() -> Self {
+        XRayClientLayer::default()
+    }
+}
+
+impl<S> Layer<S> for XRayClientLayer {
+    type Service = XRayClientService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        XRayClientService { inner }
+    }
+}
+
+/// Sends each request within a span recorded as a remote X-Ray subsegment,
+/// carrying the trace in the `X-Amzn-Trace-Id` request header
+#[derive(Clone, Debug)]
+pub struct XRayClientService<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for XRayClientService<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ClientResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        let span = call_span(
+            request.method().as_str(),
+            &request.uri().to_string(),
+            request.uri().host(),
+        );
+        inject_trace_header(&span, request.headers_mut());
+        let future = span.in_scope(|| self.inner.call(request));
+        ClientResponseFuture {
+            inner: future,
+            span,
+        }
+    }
+}
+
+pin_project! {
+    /// Response future of [`XRayClientService`]
+    pub struct ClientResponseFuture<F> {
+        #[pin]
+        inner: F,
+        span: Span,
+    }
+}
+
+impl<F, ResBody, E> Future for ClientResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _guard = this.span.enter();
+        let result = ready!(this.inner.poll(cx));
+        match &result {
+            Ok(response) => record_status(this.span, response.status().as_u16()),
+            Err(_) => this.span.xray_mark_fault(),
+        }
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sampling::DefaultSampler, types::header::Header, MemoryEmitter, XRay};
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+    use tracing_subscriber::prelude::*;
+
+    #[tokio::test]
+    async fn records_remote_subsegment() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(
+            XRay::default()
+                .with_emitter(emitter.clone())
+                .with_sampler(DefaultSampler::new(0, 1.0)),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let client = XRayClientLayer::new().layer(service_fn(|request: Request<()>| async move {
+            let trace_header = request.headers()[Header::NAME].clone();
+            let response = Response::builder()
+                .status(404)
+                .header(Header::NAME, trace_header)
+                .body(())
+                .unwrap();
+            Ok::<_, Infallible>(response)
+        }));
+        let request = Request::get("http://inventory.internal/items/7")
+            .body(())
+            .unwrap();
+        let response = {
+            let _handler = tracing::info_span!("handler").entered();
+            client.oneshot(request).await.unwrap()
+        };
+
+        let document = &emitter.documents()[0];
+        let call = &document["subsegments"][0];
+        assert_eq!(
+            response.headers()[Header::NAME],
+            format!(
+                "Root={};Parent={};Sampled=1",
+                document["trace_id"].as_str().unwrap(),
+                call["id"].as_str().unwrap()
+            )
+        );
+        assert_eq!(call["name"], "inventory.internal");
+        assert_eq!(call["namespace"], "remote");
+        assert_eq!(
+            call["http"],
+            serde_json::json!({
+                "request": {
+                    "method": "GET",
+                    "url": "http://inventory.internal/items/7",
+                    "traced": true,
+                },
+                "response": { "status": 404 },
+            })
+        );
+        assert_eq!(call["error"], true);
+    }
+
+    #[tokio::test]
+    async fn omits_subsegment_fields_outside_segments() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(
+            XRay::default()
+                .with_emitter(emitter.clone())
+                .with_sampler(DefaultSampler::new(0, 1.0)),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let client = XRayClientLayer::new().layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+        let request = Request::get("http://inventory.internal/items/7")
+            .body(())
+            .unwrap();
+        client.oneshot(request).await.unwrap();
+
+        let document = &emitter.documents()[0];
+        assert_eq!(document["name"], "inventory.internal");
+        assert!(document.get("type").is_none());
+        assert!(document.get("namespace").is_none());
+        assert!(document["http"]["request"].get("traced").is_none());
+    }
+}
@@ -0,0 +1,9 @@
+//! [`tower`] middleware recording HTTP requests as X-Ray segments
+//!
+//! Available with the `tower` feature.
+
+mod client;
+mod server;
+
+pub use client::{ClientResponseFuture, XRayClientLayer, XRayClientService};
+pub use server::{ResponseFuture, XRayLayer, XRayService};
@@ -0,0 +1,281 @@
+use crate::{span_ext::XRaySpanExt, types::header::Header};
+use http::{header::HeaderValue, HeaderMap, Request, Response};
+use pin_project_lite::pin_project;
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tower::{Layer, Service};
+use tracing::{field, Span};
+
+/// Wraps services handling inbound HTTP requests with [`XRayService`]
+///
+/// Each request is handled within a `request` span which the
+/// [`XRay`](crate::XRay) tracing layer records as a segment named after the
+/// service, continuing the trace of any incoming `X-Amzn-Trace-Id` header.
+/// The span has no parent, so requests are never recorded as subsegments of
+/// a span the server runs in.
+///
+/// ```no_run
+/// use tower::ServiceBuilder;
+/// use tracing_xray::tower::XRayLayer;
+///
+/// # let service = tower::service_fn(|_: http::Request<()>| async {
+/// #     Ok::<_, std::convert::Infallible>(http::Response::new(()))
+/// # });
+/// let service = ServiceBuilder::new()
+///     .layer(XRayLayer::new("orders"))
+///     .service(service);
+/// ```
+#[derive(Clone, Debug)]
+pub struct XRayLayer {
+    name: String,
+}
+
+impl XRayLayer {
+    /// Creates a layer recording requests as segments named `name`
+    pub fn new<N>(name: N) -> Self
+    where
+        N: Into<String>,
+    {
+        XRayLayer { name: name.into() }
+    }
+}
+
+impl<S> Layer<S> for XRayLayer {
+    type Service = XRayService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        XRayService {
+            inner,
+            name: self.name.clone(),
+        }
+    }
+}
+
+/// Handles each request within a span recorded as an X-Ray segment, and
+/// reports the trace back to the caller in the `X-Amzn-Trace-Id` response
+/// header
+#[derive(Clone, Debug)]
+pub struct XRayService<S> {
+    inner: S,
+    name: String,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for XRayService<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let headers = request.headers();
+        let client_ip = client_ip(headers);
+        // each request begins its own segment, even when the server itself
+        // runs within a span
+        let span = tracing::info_span!(
+            parent: None,
+            "request",
+            xray.name = %self.name,
+            "x-amzn-trace-id" = header(headers, Header::NAME),
+            http.method = %request.method(),
+            http.url = %url(&request),
+            http.user_agent = header(headers, http::header::USER_AGENT.as_str()),
+            http.client_ip = client_ip,
+            http.x_forwarded_for = client_ip.map(|_| true),
+            http.status_code = field::Empty,
+        );
+        let future = span.in_scope(|| self.inner.call(request));
+        ResponseFuture {
+            inner: future,
+            span,
+        }
+    }
+}
+
+pin_project! {
+    /// Response future of [`XRayService`]
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: F,
+        span: Span,
+    }
+}
+
+impl<F, ResBody, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let _guard = this.span.enter();
+        match ready!(this.inner.poll(cx)) {
+            Ok(mut response) => {
+                this.span
+                    .record("http.status_code", response.status().as_u16());
+                let trace_header = this
+                    .span
+                    .xray_response_header()
+                    .and_then(|header| HeaderValue::try_from(header.to_string()).ok());
+                if let Some(value) = trace_header {
+                    response.headers_mut().insert(Header::NAME, value);
+                }
+                Poll::Ready(Ok(response))
+            }
+            Err(e) => {
+                this.span.xray_mark_fault();
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// The full URL of a request, which servers usually receive as a bare path
+/// alongside a `Host` header
+fn url<B>(request: &Request<B>) -> String {
+    let uri = request.uri();
+    match (uri.authority(), header(request.headers(), "host")) {
+        (None, Some(host)) => format!(
+            "{}://{}{}",
+            uri.scheme_str().unwrap_or("http"),
+            host,
+            uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/")
+        ),
+        _ => uri.to_string(),
+    }
+}
+
+/// The address of the original client of a request forwarded by a proxy or
+/// load balancer
+fn client_ip(headers: &HeaderMap) -> Option<&str> {
+    header(headers, "x-forwarded-for")
+        .and_then(|forwarded| forwarded.split(',').next())
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sampling::DefaultSampler, MemoryEmitter, XRay};
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+    use tracing_subscriber::prelude::*;
+
+    #[tokio::test]
+    async fn reports_sampling_decision_upstream() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(
+            XRay::default()
+                .with_emitter(emitter.clone())
+                .with_sampler(DefaultSampler::new(0, 0.0)),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let service = XRayLayer::new("orders").layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+        let request = Request::get("/orders")
+            .header(
+                Header::NAME,
+                "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=?",
+            )
+            .body(())
+            .unwrap();
+        let response = service.oneshot(request).await.unwrap();
+
+        assert_eq!(
+            response.headers()[Header::NAME],
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=0"
+        );
+        assert!(emitter.documents().is_empty());
+    }
+
+    #[tokio::test]
+    async fn records_segment_with_http_block() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(
+            XRay::default()
+                .with_emitter(emitter.clone())
+                .with_sampler(DefaultSampler::new(0, 1.0)),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let service = XRayLayer::new("orders").layer(service_fn(|_: Request<()>| async {
+            let response = Response::builder().status(503).body(()).unwrap();
+            Ok::<_, Infallible>(response)
+        }));
+        let request = Request::get("/orders/7?page=2")
+            .header("host", "api.example.com")
+            .header("user-agent", "curl/8.0")
+            .header("x-forwarded-for", "203.0.113.7, 10.0.0.1")
+            .body(())
+            .unwrap();
+        let response = service.oneshot(request).await.unwrap();
+
+        let document = &emitter.documents()[0];
+        assert_eq!(document["name"], "orders");
+        assert_eq!(
+            response.headers()[Header::NAME],
+            format!("Root={};Sampled=1", document["trace_id"].as_str().unwrap())
+        );
+        assert_eq!(
+            document["http"],
+            serde_json::json!({
+                "request": {
+                    "method": "GET",
+                    "url": "http://api.example.com/orders/7?page=2",
+                    "user_agent": "curl/8.0",
+                    "client_ip": "203.0.113.7",
+                    "x_forwarded_for": true,
+                },
+                "response": { "status": 503 },
+            })
+        );
+        assert_eq!(document["fault"], true);
+    }
+
+    #[tokio::test]
+    async fn records_each_request_as_its_own_segment() {
+        let emitter = MemoryEmitter::new();
+        let subscriber = tracing_subscriber::registry().with(
+            XRay::default()
+                .with_emitter(emitter.clone())
+                .with_sampler(DefaultSampler::new(0, 1.0)),
+        );
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let server = tracing::info_span!("server");
+        let _entered = server.enter();
+        let service = XRayLayer::new("orders").layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+        let mut roots = Vec::new();
+        for _ in 0..2 {
+            let request = Request::get("/orders").body(()).unwrap();
+            let response = service.clone().oneshot(request).await.unwrap();
+            roots.push(response.headers()[Header::NAME].clone());
+        }
+
+        assert_ne!(roots[0], roots[1]);
+        let documents = emitter.documents();
+        assert_eq!(documents.len(), 2);
+        for document in &documents {
+            assert_eq!(document["name"], "orders");
+            assert!(document.get("type").is_none());
+            assert!(document.get("parent_id").is_none());
+        }
+    }
+}
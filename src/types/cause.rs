@@ -0,0 +1,237 @@
+//! Construction of X-Ray error descriptions from Rust errors and backtraces
+
+use super::{
+    ids::SegmentId,
+    types::{Cause, Exception, Segment, StackFrame},
+};
+use std::{backtrace::Backtrace, env, error::Error, path::Path};
+
+/// Maximum number of stack frames recorded for an exception
+const MAX_STACK_FRAMES: usize = 50;
+
+impl Cause {
+    /// Describes an error by its exceptions, recording the process's current
+    /// working directory and executable
+    pub fn from_exceptions(exceptions: Vec<Exception>) -> Self {
+        Cause::Description {
+            working_directory: working_directory(),
+            paths: env::current_exe()
+                .map(|exe| vec![exe.display().to_string()])
+                .unwrap_or_default(),
+            exceptions,
+        }
+    }
+
+    /// Describes `error` and the chain of errors which caused it
+    ///
+    /// Each error in the chain, followed through [`Error::source`], becomes an
+    /// exception whose `cause` is the id of the exception for its source.
+    pub fn from_error(error: &(dyn Error + 'static)) -> Self {
+        Cause::from_exceptions(Exception::chain(error))
+    }
+
+    /// Records the frames of `backtrace` as the stack of the first exception,
+    /// the error the cause was created from
+    pub fn with_backtrace(mut self, backtrace: &Backtrace) -> Self {
+        if let Cause::Description { exceptions, .. } = &mut self {
+            if let Some(exception) = exceptions.first_mut() {
+                exception.set_backtrace(backtrace);
+            }
+        }
+        self
+    }
+
+    /// Adds an exception to a cause description. A cause referring to an
+    /// exception by id is replaced by a description of the new exception
+    pub fn push(&mut self, exception: Exception) -> &mut Self {
+        match self {
+            Cause::Description { exceptions, .. } => exceptions.push(exception),
+            Cause::Name(_) => *self = Cause::from_exceptions(vec![exception]),
+        }
+        self
+    }
+}
+
+impl Segment {
+    /// Adds exceptions to the segment's cause, creating a cause description if
+    /// there is none
+    pub fn add_exceptions<I>(&mut self, exceptions: I) -> &mut Self
+    where
+        I: IntoIterator<Item = Exception>,
+    {
+        for exception in exceptions {
+            match &mut self.cause {
+                Some(cause) => {
+                    cause.push(exception);
+                }
+                None => self.cause = Some(Cause::from_exceptions(vec![exception])),
+            }
+        }
+        self
+    }
+}
+
+impl Exception {
+    /// Creates an exception with a new random id describing `message`
+    pub fn new<M>(message: M) -> Self
+    where
+        M: Into<String>,
+    {
+        Exception {
+            id: SegmentId::new().to_string(),
+            message: Some(message.into()),
+            kind: None,
+            remote: None,
+            truncated: None,
+            skipped: None,
+            cause: None,
+            stack: Vec::new(),
+        }
+    }
+
+    /// Creates exceptions for `error` and each error in its chain of sources,
+    /// linked through their `cause` ids
+    pub fn chain(error: &(dyn Error + 'static)) -> Vec<Exception> {
+        let mut exceptions: Vec<Exception> = Vec::new();
+        let mut next = Some(error);
+        while let Some(error) = next {
+            let exception = Exception::new(error.to_string());
+            if let Some(caused) = exceptions.last_mut() {
+                caused.cause = Some(exception.id.clone());
+            }
+            exceptions.push(exception);
+            next = error.source();
+        }
+        exceptions
+    }
+
+    /// Records the frames of a captured backtrace as this exception's stack
+    pub fn set_backtrace(&mut self, backtrace: &Backtrace) -> &mut Self {
+        let mut frames = parse_backtrace(&backtrace.to_string(), &working_directory());
+        if frames.len() > MAX_STACK_FRAMES {
+            self.truncated = Some(frames.len() - MAX_STACK_FRAMES);
+            frames.truncate(MAX_STACK_FRAMES);
+        }
+        self.stack = frames;
+        self
+    }
+}
+
+fn working_directory() -> String {
+    env::current_dir()
+        .map(|dir| dir.display().to_string())
+        .unwrap_or_default()
+}
+
+/// Parses the display output of a captured [`Backtrace`]
+///
+/// Frames are rendered as an index and symbol name, optionally followed by an
+/// `at path:line:column` location line. Paths within `working_directory` are
+/// made relative to it.
+fn parse_backtrace(rendered: &str, working_directory: &str) -> Vec<StackFrame> {
+    let mut frames: Vec<StackFrame> = Vec::new();
+    for line in rendered.lines().map(str::trim) {
+        if let Some(location) = line.strip_prefix("at ") {
+            if let Some(frame) = frames.last_mut() {
+                let (path, line) = split_location(location);
+                let path = Path::new(path)
+                    .strip_prefix(working_directory)
+                    .map(|relative| relative.display().to_string())
+                    .unwrap_or_else(|_| path.into());
+                frame.path = Some(path);
+                frame.line = line;
+            }
+        } else if let Some((index, label)) = line.split_once(": ") {
+            if index.chars().all(|c| c.is_ascii_digit()) {
+                frames.push(StackFrame {
+                    path: None,
+                    line: None,
+                    label: Some(label.into()),
+                });
+            }
+        }
+    }
+    frames
+}
+
+/// Splits a `path:line:column` location into its path and line
+fn split_location(location: &str) -> (&str, Option<u32>) {
+    let mut parts = location.rsplitn(3, ':');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(column), Some(line), Some(path)) if column.parse::<u32>().is_ok() => {
+            (path, line.parse().ok())
+        }
+        _ => (location, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt;
+
+    #[derive(Debug)]
+    struct Failure(&'static str, Option<Box<Failure>>);
+
+    impl fmt::Display for Failure {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(self.0)
+        }
+    }
+
+    impl Error for Failure {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            self.1
+                .as_deref()
+                .map(|source| source as &(dyn Error + 'static))
+        }
+    }
+
+    #[test]
+    fn links_error_chain() {
+        let error = Failure(
+            "request failed",
+            Some(Box::new(Failure(
+                "query failed",
+                Some(Box::new(Failure("connection reset", None))),
+            ))),
+        );
+        let exceptions = match Cause::from_error(&error) {
+            Cause::Description { exceptions, .. } => exceptions,
+            cause => panic!("unexpected cause {:?}", cause),
+        };
+        let messages = exceptions
+            .iter()
+            .map(|exception| exception.message.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            vec!["request failed", "query failed", "connection reset"]
+        );
+        assert_eq!(exceptions[0].cause.as_ref(), Some(&exceptions[1].id));
+        assert_eq!(exceptions[1].cause.as_ref(), Some(&exceptions[2].id));
+        assert_eq!(exceptions[2].cause, None);
+        assert!(exceptions.iter().all(|exception| exception.id.len() == 16));
+    }
+
+    #[test]
+    fn parses_backtrace_frames() {
+        let rendered = "   0: app::handler
+             at /srv/app/src/handler.rs:42:9
+   1: core::ops::function::FnOnce::call_once
+             at /rustc/abc/library/core/src/ops/function.rs:250:5
+   2: main
+";
+        let frames = parse_backtrace(rendered, "/srv/app");
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].label.as_deref(), Some("app::handler"));
+        assert_eq!(frames[0].path.as_deref(), Some("src/handler.rs"));
+        assert_eq!(frames[0].line, Some(42));
+        assert_eq!(
+            frames[1].path.as_deref(),
+            Some("/rustc/abc/library/core/src/ops/function.rs")
+        );
+        assert_eq!(frames[2].label.as_deref(), Some("main"));
+        assert_eq!(frames[2].path, None);
+    }
+}
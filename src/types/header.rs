@@ -1,14 +1,18 @@
 //! X-Ray [tracing header](https://docs.aws.amazon.com/xray/latest/devguide/xray-concepts.html?shortFooter=true#xray-concepts-tracingheader)
 //! parser
 
-use crate::types::ids::{SegmentId, TraceId};
+use crate::{
+    error::XRayError,
+    types::ids::{SegmentId, TraceId},
+};
 use std::{
-    collections::HashMap,
     fmt::{self, Display},
     str::FromStr,
 };
 
-#[derive(PartialEq, Debug)]
+/// The sampling decision carried by a trace header
+#[derive(PartialEq, Debug, Default, Clone, Copy)]
+#[non_exhaustive]
 pub enum SamplingDecision {
     /// Sampled indicates the current segment has been
     /// sampled and will be sent to the X-Ray daemon.
@@ -21,6 +25,7 @@ pub enum SamplingDecision {
     /// back upstream in the response.
     Requested,
     /// Unknown indicates no sampling decision will be made.
+    #[default]
     Unknown,
 }
 
@@ -51,19 +56,99 @@ impl Display for SamplingDecision {
     }
 }
 
-impl Default for SamplingDecision {
-    fn default() -> Self {
-        SamplingDecision::Unknown
+/// The `Lineage` field of a trace header, which AWS Lambda uses to detect
+/// functions invoking themselves in a loop through other services
+///
+/// Rendered as `{hash}:{counter}`, where the hash is 8 hexadecimal digits
+/// identifying the origin of the loop and the counter is the number of
+/// invocations along it so far.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Lineage {
+    hash: u32,
+    counter: u32,
+}
+
+impl Lineage {
+    /// Creates a lineage from its hash and invocation counter
+    pub fn new(hash: u32, counter: u32) -> Self {
+        Lineage { hash, counter }
+    }
+
+    /// The hash identifying the origin of the loop
+    pub fn hash(&self) -> u32 {
+        self.hash
+    }
+
+    /// The number of invocations along the loop so far
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    /// The lineage to propagate to a downstream call, with the counter
+    /// incremented
+    pub fn next(&self) -> Self {
+        Lineage {
+            counter: self.counter.saturating_add(1),
+            ..*self
+        }
+    }
+}
+
+impl FromStr for Lineage {
+    type Err = XRayError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            |reason: &str| XRayError::InvalidHeader(format!("invalid Lineage `{}`: {}", s, reason));
+        let (hash, counter) = s
+            .split_once(':')
+            .ok_or_else(|| invalid("expected hash:counter"))?;
+        if hash.len() != 8 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid("expected a hash of 8 hexadecimal digits"));
+        }
+        if counter.is_empty() || !counter.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid("expected a decimal counter"));
+        }
+        Ok(Lineage {
+            hash: u32::from_str_radix(hash, 16).map_err(|_| invalid("invalid hash"))?,
+            counter: counter
+                .parse()
+                .map_err(|_| invalid("counter out of range"))?,
+        })
+    }
+}
+
+impl Display for Lineage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}:{}", self.hash, self.counter)
     }
 }
 
 /// Parsed representation of `X-Amzn-Trace-Id` request header
-#[derive(PartialEq, Debug, Default)]
+///
+/// Parsing tolerates whitespace around fields and accepts keys in any case.
+/// Fields other than `Root`, `Parent`, `Sampled` and `Lineage`, such as the
+/// `Self` field added by load balancers, are kept in their original order, so
+/// a well-formed header displays exactly as it was received.
+#[derive(PartialEq, Debug, Default, Clone)]
 pub struct Header {
     pub(crate) trace_id: TraceId,
     pub(crate) parent_id: Option<SegmentId>,
     pub(crate) sampling_decision: SamplingDecision,
-    additional_data: HashMap<String, String>,
+    pub(crate) lineage: Option<Lineage>,
+    additional_data: Vec<(String, String)>,
+    /// Order of the fields of a parsed header
+    layout: Vec<Field>,
+}
+
+/// A field of the header, where `Data` stands for the next additional
+/// `key=value` pair
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Field {
+    Root,
+    Parent,
+    Sampled,
+    Lineage,
+    Data,
 }
 
 impl Header {
@@ -72,6 +157,11 @@ impl Header {
     /// HTTP header values should be the Display serialization of Header structs
     pub const NAME: &'static str = "X-Amzn-Trace-Id";
 
+    /// Maximum length, in bytes, of a header value accepted when parsing
+    pub const MAX_LENGTH: usize = 256;
+
+    /// Creates a header for the trace `trace_id`, without a parent or
+    /// sampling decision
     pub fn new(trace_id: TraceId) -> Self {
         Header {
             trace_id,
@@ -79,64 +169,191 @@ impl Header {
         }
     }
 
+    /// The id of the trace
+    pub fn trace_id(&self) -> &TraceId {
+        &self.trace_id
+    }
+
+    /// The id of the upstream (sub)segment which made the request, if any
+    pub fn parent_id(&self) -> Option<&SegmentId> {
+        self.parent_id.as_ref()
+    }
+
+    /// The upstream sampling decision
+    pub fn sampling_decision(&self) -> SamplingDecision {
+        self.sampling_decision
+    }
+
+    /// The lineage of the request, if it was invoked by AWS Lambda or a
+    /// downstream service of a Lambda function
+    pub fn lineage(&self) -> Option<Lineage> {
+        self.lineage
+    }
+
+    /// Returns the value of an additional `key=value` pair carried by the
+    /// header, such as `Self`. Keys are matched regardless of case.
+    pub fn data(&self, key: &str) -> Option<&str> {
+        self.additional_data
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The additional `key=value` pairs carried by the header, in order
+    pub fn additional_data(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.additional_data
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Sets the id of the (sub)segment making the request
     pub fn with_parent_id(&mut self, parent_id: SegmentId) -> &mut Self {
         self.parent_id = Some(parent_id);
         self
     }
 
+    /// Sets the sampling decision
     pub fn with_sampling_decision(&mut self, decision: SamplingDecision) -> &mut Self {
         self.sampling_decision = decision;
         self
     }
 
+    /// Sets the lineage
+    pub fn with_lineage(&mut self, lineage: Lineage) -> &mut Self {
+        self.lineage = Some(lineage);
+        self
+    }
+
+    /// Adds an additional `key=value` pair, replacing the value of an
+    /// existing pair with the same key
     pub fn with_data<K, V>(&mut self, key: K, value: V) -> &mut Self
     where
         K: Into<String>,
         V: Into<String>,
     {
-        self.additional_data.insert(key.into(), value.into());
+        let (key, value) = (key.into(), value.into());
+        match self
+            .additional_data
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(&key))
+        {
+            Some((_, existing)) => *existing = value,
+            None => self.additional_data.push((key, value)),
+        }
         self
     }
+
+    fn has(&self, field: Field) -> bool {
+        match field {
+            Field::Root => true,
+            Field::Parent => self.parent_id.is_some(),
+            Field::Sampled => self.sampling_decision != SamplingDecision::Unknown,
+            Field::Lineage => self.lineage.is_some(),
+            Field::Data => false,
+        }
+    }
 }
 
 impl FromStr for Header {
-    type Err = String;
+    type Err = XRayError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        s.split(';')
-            .try_fold(Header::default(), |mut header, line| {
-                if line.starts_with("Root=") {
-                    header.trace_id = TraceId::Rendered(
-                        line.strip_prefix("Root=").unwrap_or_default().to_string(),
-                    )
-                } else if line.starts_with("Parent=") {
-                    header.parent_id = Some(SegmentId::Rendered(
-                        line.strip_prefix("Parent=").unwrap_or_default().to_string(),
-                    ))
-                } else if line.starts_with("Sampled=") {
-                    header.sampling_decision = line.into();
-                } else if !line.starts_with("Self=") {
-                    let pos = line
-                        .find('=')
-                        .ok_or_else(|| format!("invalid key=value: no `=` found in `{}`", s))?;
-                    let (key, value) = (&line[..pos], &line[pos + 1..]);
-                    header.additional_data.insert(key.into(), value.into());
+        if s.len() > Header::MAX_LENGTH {
+            return Err(XRayError::InvalidHeader(format!(
+                "{} bytes exceeds the limit of {}",
+                s.len(),
+                Header::MAX_LENGTH
+            )));
+        }
+        let mut header = Header::default();
+        for entry in s
+            .split(';')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (key, value) = entry
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .filter(|(key, _)| !key.is_empty())
+                .ok_or_else(|| {
+                    XRayError::InvalidHeader(format!("expected key=value, found `{}`", entry))
+                })?;
+            let field = if key.eq_ignore_ascii_case("Root") {
+                Field::Root
+            } else if key.eq_ignore_ascii_case("Parent") {
+                Field::Parent
+            } else if key.eq_ignore_ascii_case("Sampled") {
+                Field::Sampled
+            } else if key.eq_ignore_ascii_case("Lineage") {
+                Field::Lineage
+            } else {
+                Field::Data
+            };
+            if field != Field::Data && header.layout.contains(&field) {
+                return Err(XRayError::InvalidHeader(format!("duplicate `{}`", key)));
+            }
+            match field {
+                Field::Root => header.trace_id = value.parse()?,
+                Field::Parent => header.parent_id = Some(value.parse()?),
+                Field::Sampled => {
+                    header.sampling_decision = match value {
+                        "1" => SamplingDecision::Sampled,
+                        "0" => SamplingDecision::NotSampled,
+                        "?" => SamplingDecision::Requested,
+                        _ => {
+                            return Err(XRayError::InvalidHeader(format!(
+                                "invalid Sampled `{}`",
+                                value
+                            )))
+                        }
+                    }
                 }
-                Ok(header)
-            })
+                Field::Lineage => header.lineage = Some(value.parse()?),
+                Field::Data => header.additional_data.push((key.into(), value.into())),
+            }
+            header.layout.push(field);
+        }
+        if !header.layout.contains(&Field::Root) {
+            return Err(XRayError::InvalidHeader(format!(
+                "no Root found in `{}`",
+                s
+            )));
+        }
+        Ok(header)
     }
 }
 
 impl Display for Header {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Root={}", self.trace_id)?;
-        if let Some(parent) = &self.parent_id {
-            write!(f, ";Parent={}", parent)?;
-        }
-        if self.sampling_decision != SamplingDecision::Unknown {
-            write!(f, ";{}", self.sampling_decision)?;
+        // parsed fields keep their order, followed by any added since
+        let mut layout = self.layout.clone();
+        for field in [Field::Root, Field::Parent, Field::Sampled, Field::Lineage] {
+            if self.has(field) && !layout.contains(&field) {
+                layout.push(field);
+            }
         }
-        for (k, v) in &self.additional_data {
-            write!(f, ";{}={}", k, v)?;
+        let mut data = self.additional_data.iter();
+        let mut separator = "";
+        for field in layout.into_iter().chain(std::iter::repeat(Field::Data)) {
+            match field {
+                Field::Root => write!(f, "{}Root={}", separator, self.trace_id)?,
+                Field::Parent => match &self.parent_id {
+                    Some(parent) => write!(f, "{}Parent={}", separator, parent)?,
+                    None => continue,
+                },
+                Field::Sampled if self.has(Field::Sampled) => {
+                    write!(f, "{}{}", separator, self.sampling_decision)?
+                }
+                Field::Sampled => continue,
+                Field::Lineage => match &self.lineage {
+                    Some(lineage) => write!(f, "{}Lineage={}", separator, lineage)?,
+                    None => continue,
+                },
+                Field::Data => match data.next() {
+                    Some((key, value)) => write!(f, "{}{}={}", separator, key, value)?,
+                    None => break,
+                },
+            }
+            separator = ";";
         }
         Ok(())
     }
@@ -145,41 +362,152 @@ impl Display for Header {
 #[cfg(test)]
 mod tests {
     use super::*;
-    #[test]
-    fn parse_with_parent_from_str() {
-        assert_eq!(
-            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1"
-                .parse::<Header>(),
-            Ok(Header {
-                trace_id: TraceId::Rendered("1-5759e988-bd862e3fe1be46a994272793".into()),
-                parent_id: Some(SegmentId::Rendered("53995c3f42cd8ad8".into())),
-                sampling_decision: SamplingDecision::Sampled,
-                ..Header::default()
-            })
+
+    fn trace_id() -> TraceId {
+        TraceId::from_parts(
+            0x5759e988,
+            *b"\xbd\x86\x2e\x3f\xe1\xbe\x46\xa9\x94\x27\x27\x93",
         )
     }
+
     #[test]
-    fn parse_no_parent_from_str() {
+    fn parse_with_parent_from_str() -> Result<(), XRayError> {
+        let header: Header =
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1".parse()?;
+        assert_eq!(header.trace_id(), &trace_id());
         assert_eq!(
-            "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1".parse::<Header>(),
-            Ok(Header {
-                trace_id: TraceId::Rendered("1-5759e988-bd862e3fe1be46a994272793".into()),
-                parent_id: None,
-                sampling_decision: SamplingDecision::Sampled,
-                ..Header::default()
-            })
-        )
+            header.parent_id(),
+            Some(&SegmentId::from_bytes(*b"\x53\x99\x5c\x3f\x42\xcd\x8a\xd8"))
+        );
+        assert_eq!(header.sampling_decision(), SamplingDecision::Sampled);
+        assert_eq!(header.additional_data().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_no_parent_from_str() -> Result<(), XRayError> {
+        let header: Header = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1".parse()?;
+        assert_eq!(header.trace_id(), &trace_id());
+        assert_eq!(header.parent_id(), None);
+        assert_eq!(header.sampling_decision(), SamplingDecision::Sampled);
+        Ok(())
     }
 
     #[test]
     fn displays_as_header() {
-        let header = Header {
-            trace_id: TraceId::Rendered("1-5759e988-bd862e3fe1be46a994272793".into()),
-            ..Header::default()
-        };
+        let mut header = Header::new(trace_id());
         assert_eq!(
             format!("{}", header),
             "Root=1-5759e988-bd862e3fe1be46a994272793"
         );
+        header
+            .with_data("CalledFrom", "app")
+            .with_sampling_decision(SamplingDecision::NotSampled)
+            .with_parent_id(SegmentId::from_bytes([1; 8]));
+        assert_eq!(
+            format!("{}", header),
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=0101010101010101;Sampled=0;CalledFrom=app"
+        );
+    }
+
+    #[test]
+    fn round_trips_real_headers() {
+        for raw in [
+            // X-Ray documentation
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1",
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1",
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=?",
+            // application load balancer
+            "Root=1-67891233-abcdef012345678912345678",
+            "Self=1-67891233-12456789abcdef012345678;Root=1-67891233-abcdef012345678912345678",
+            "Self=1-67891234-12456789abcdef012345678;Root=1-67891233-abcdef012345678912345678;CalledFrom=app",
+            // api gateway
+            "Root=1-5e1b4151-5ac6c58f5b5daa6532e4f0b2;Parent=6b1a3e2c9d8f7a01;Sampled=0",
+            // lambda `_X_AMZN_TRACE_ID`
+            "Root=1-65f3b1c2-4a1e7d9c0b2f3e8d6c5a4b3f;Parent=1f2e3d4c5b6a7980;Sampled=1;Lineage=a87bd80c:0",
+            "Root=1-65f3b1c2-4a1e7d9c0b2f3e8d6c5a4b3f;Parent=1f2e3d4c5b6a7980;Sampled=0;Lineage=2d3a4f1e:12",
+            // fields in an unusual order
+            "Sampled=1;Parent=53995c3f42cd8ad8;Root=1-5759e988-bd862e3fe1be46a994272793",
+        ] {
+            let header = raw
+                .parse::<Header>()
+                .unwrap_or_else(|e| panic!("failed to parse {}: {}", raw, e));
+            assert_eq!(header.to_string(), raw);
+        }
+    }
+
+    #[test]
+    fn tolerates_whitespace_and_case() -> Result<(), XRayError> {
+        let header: Header =
+            " root = 1-5759E988-BD862E3FE1BE46A994272793 ; PARENT=53995c3f42cd8ad8;sampled=1;self=x; "
+                .parse()?;
+        assert_eq!(header.trace_id(), &trace_id());
+        assert_eq!(header.sampling_decision(), SamplingDecision::Sampled);
+        assert_eq!(header.data("Self"), Some("x"));
+        assert_eq!(
+            header.to_string(),
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1;self=x"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parses_lineage() -> Result<(), XRayError> {
+        let header: Header =
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1;Lineage=a87bd80c:3".parse()?;
+        assert_eq!(header.lineage(), Some(Lineage::new(0xa87bd80c, 3)));
+        assert_eq!(header.data("Lineage"), None);
+        assert_eq!(
+            header.to_string(),
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1;Lineage=a87bd80c:3"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn validates_lineage() {
+        assert_eq!(
+            "0000abcd:12".parse::<Lineage>().ok(),
+            Some(Lineage::new(0xabcd, 12))
+        );
+        assert_eq!(Lineage::new(0xabcd, 12).to_string(), "0000abcd:12");
+        assert_eq!(Lineage::new(0xabcd, 12).next().counter(), 13);
+        assert_eq!(Lineage::new(0xabcd, u32::MAX).next().counter(), u32::MAX);
+        for invalid in [
+            "",
+            "a87bd80c",
+            "a87bd80c:",
+            "a87bd80:1",
+            "a87bd80cc:1",
+            "a87bd80g:1",
+            "a87bd80c:-1",
+            "a87bd80c:+1",
+            "a87bd80c:99999999999",
+            "a87bd80c:1:2",
+        ] {
+            assert!(invalid.parse::<Lineage>().is_err(), "accepted {}", invalid);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let root = "Root=1-5759e988-bd862e3fe1be46a994272793";
+        for raw in [
+            String::new(),
+            " ; ".into(),
+            "Parent=53995c3f42cd8ad8;Sampled=1".into(),
+            "Root=1-5759e988".into(),
+            format!("{};Parent=53995c3f", root),
+            format!("{};{}", root, root),
+            format!("{};Lineage=a87bd80c:1;Lineage=a87bd80c:2", root),
+            format!("{};Lineage=not-a-lineage", root),
+            format!("{};Sampled=x", root),
+            format!("{};Sampled=", root),
+            format!("{};garbage", root),
+            format!("{};=value", root),
+            format!("{};Padding={}", root, "x".repeat(Header::MAX_LENGTH)),
+        ] {
+            assert!(raw.parse::<Header>().is_err(), "accepted {}", raw);
+        }
     }
 }
@@ -1,32 +1,38 @@
 use super::{time::Seconds, types::Bytes};
+use crate::error::XRayError;
 use rand::RngCore;
 use serde::{de, ser, Serializer};
-use std::fmt;
+use std::{error::Error, fmt, str::FromStr};
 
 /// Unique identifier of an operation within a trace
-#[derive(Debug, PartialEq, Clone)]
-pub enum SegmentId {
-    #[doc(hidden)]
-    New([u8; 8]),
-    #[doc(hidden)]
-    Rendered(String),
-}
+///
+/// Rendered as 16 lowercase hexadecimal digits. Parsing also accepts
+/// uppercase digits.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct SegmentId([u8; 8]);
 
 impl SegmentId {
     /// Generate a new random segment ID
     pub fn new() -> Self {
         let mut buf = [0; 8];
         rand::thread_rng().fill_bytes(&mut buf);
-        SegmentId::New(buf)
+        SegmentId(buf)
+    }
+
+    /// Creates a segment ID from its 8 bytes
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        SegmentId(bytes)
+    }
+
+    /// The 8 bytes of the ID
+    pub fn as_bytes(&self) -> &[u8; 8] {
+        &self.0
     }
 }
 
 impl fmt::Display for SegmentId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            SegmentId::New(bytes) => write!(f, "{:x}", Bytes(bytes)),
-            SegmentId::Rendered(value) => write!(f, "{}", value),
-        }
+        write!(f, "{:x}", Bytes(&self.0))
     }
 }
 
@@ -36,20 +42,11 @@ impl Default for SegmentId {
     }
 }
 
-struct SegmentIdVisitor;
-
-impl<'de> de::Visitor<'de> for SegmentIdVisitor {
-    type Value = SegmentId;
+impl FromStr for SegmentId {
+    type Err = XRayError;
 
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a string value")
-    }
-
-    fn visit_str<E>(self, value: &str) -> Result<SegmentId, E>
-    where
-        E: de::Error,
-    {
-        Ok(SegmentId::Rendered(value.into()))
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(SegmentId(decode_hex(s, 0)?))
     }
 }
 
@@ -58,7 +55,7 @@ impl ser::Serialize for SegmentId {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&format!("{}", self))
+        serializer.collect_str(self)
     }
 }
 
@@ -67,16 +64,19 @@ impl<'de> de::Deserialize<'de> for SegmentId {
     where
         D: de::Deserializer<'de>,
     {
-        deserializer.deserialize_str(SegmentIdVisitor)
+        deserializer.deserialize_str(FromStrVisitor::new("a 16 digit hexadecimal segment id"))
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub enum TraceId {
-    #[doc(hidden)]
-    New(u64, [u8; 12]),
-    #[doc(hidden)]
-    Rendered(String),
+/// Unique identifier connecting all segments of a single request
+///
+/// Rendered as `1-{epoch}-{random}`: the version `1`, the time the trace
+/// began in 8 hexadecimal digits of epoch seconds, and 24 hexadecimal digits
+/// of random data. Parsing also accepts uppercase digits.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct TraceId {
+    epoch: u32,
+    random: [u8; 12],
 }
 
 impl TraceId {
@@ -84,7 +84,23 @@ impl TraceId {
     pub fn new() -> Self {
         let mut buf = [0; 12];
         rand::thread_rng().fill_bytes(&mut buf);
-        TraceId::New(Seconds::now().trunc(), buf)
+        TraceId::from_parts(Seconds::now().trunc() as u32, buf)
+    }
+
+    /// Creates a trace ID from the epoch seconds at which the trace began and
+    /// its 12 random bytes
+    pub fn from_parts(epoch: u32, random: [u8; 12]) -> Self {
+        TraceId { epoch, random }
+    }
+
+    /// The epoch seconds at which the trace began
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// The 12 random bytes of the ID
+    pub fn random(&self) -> &[u8; 12] {
+        &self.random
     }
 }
 
@@ -96,26 +112,34 @@ impl Default for TraceId {
 
 impl fmt::Display for TraceId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            TraceId::New(seconds, bytes) => write!(f, "1-{:08x}-{:x}", seconds, Bytes(bytes)),
-            TraceId::Rendered(value) => write!(f, "{}", value),
-        }
+        write!(f, "1-{:08x}-{:x}", self.epoch, Bytes(&self.random))
     }
 }
 
-struct TraceIdVisitor;
+impl FromStr for TraceId {
+    type Err = XRayError;
 
-impl<'de> de::Visitor<'de> for TraceIdVisitor {
-    type Value = TraceId;
-
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a string value")
-    }
-    fn visit_str<E>(self, value: &str) -> Result<TraceId, E>
-    where
-        E: de::Error,
-    {
-        Ok(TraceId::Rendered(value.into()))
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // `1-` + 8 digits + `-` + 24 digits
+        if s.len() != 35 {
+            return Err(IdError::Length {
+                expected: 35,
+                found: s.len(),
+            }
+            .into());
+        }
+        if let Some(position) = s.bytes().position(|b| !b.is_ascii()) {
+            return Err(IdError::Digit { position }.into());
+        }
+        if !s.starts_with("1-") {
+            return Err(IdError::Version.into());
+        }
+        if s.as_bytes()[10] != b'-' {
+            return Err(IdError::Separator { position: 10 }.into());
+        }
+        let epoch = u32::from_be_bytes(decode_hex(&s[2..10], 2)?);
+        let random = decode_hex(&s[11..], 11)?;
+        Ok(TraceId { epoch, random })
     }
 }
 
@@ -124,7 +148,7 @@ impl ser::Serialize for TraceId {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&format!("{}", self))
+        serializer.collect_str(self)
     }
 }
 
@@ -133,6 +157,193 @@ impl<'de> de::Deserialize<'de> for TraceId {
     where
         D: de::Deserializer<'de>,
     {
-        deserializer.deserialize_str(TraceIdVisitor)
+        deserializer.deserialize_str(FromStrVisitor::new("an X-Ray trace id"))
+    }
+}
+
+/// Describes why a [`TraceId`] or [`SegmentId`] could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum IdError {
+    /// The ID does not have the expected number of characters
+    Length {
+        /// The number of characters of a valid ID
+        expected: usize,
+        /// The number of bytes found
+        found: usize,
+    },
+    /// The trace ID does not begin with the supported version, `1-`
+    Version,
+    /// The trace ID is missing the `-` separating its epoch and random parts
+    Separator {
+        /// The byte offset at which a `-` was expected
+        position: usize,
+    },
+    /// The ID contains a character which is not a hexadecimal digit
+    Digit {
+        /// The byte offset of the invalid character
+        position: usize,
+    },
+}
+
+impl fmt::Display for IdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdError::Length { expected, found } => {
+                write!(f, "expected {} characters, found {}", expected, found)
+            }
+            IdError::Version => write!(f, "unsupported version, expected `1-`"),
+            IdError::Separator { position } => write!(f, "expected `-` at {}", position),
+            IdError::Digit { position } => write!(f, "invalid hexadecimal digit at {}", position),
+        }
+    }
+}
+
+impl Error for IdError {}
+
+/// Decodes exactly `N` bytes of hexadecimal digits, reporting the position of
+/// invalid digits relative to `offset`
+fn decode_hex<const N: usize>(s: &str, offset: usize) -> Result<[u8; N], IdError> {
+    if s.len() != N * 2 {
+        return Err(IdError::Length {
+            expected: N * 2,
+            found: s.len(),
+        });
+    }
+    let digit = |position: usize| {
+        char::from(s.as_bytes()[position])
+            .to_digit(16)
+            .map(|digit| digit as u8)
+            .ok_or(IdError::Digit {
+                position: offset + position,
+            })
+    };
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = digit(2 * i)? << 4 | digit(2 * i + 1)?;
+    }
+    Ok(bytes)
+}
+
+/// Deserializes a string through its type's [`FromStr`] implementation
+struct FromStrVisitor<T> {
+    expecting: &'static str,
+    _marker: std::marker::PhantomData<T>,
+}
+
+impl<T> FromStrVisitor<T> {
+    fn new(expecting: &'static str) -> Self {
+        FromStrVisitor {
+            expecting,
+            _marker: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<'de, T> de::Visitor<'de> for FromStrVisitor<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(self.expecting)
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<T, E>
+    where
+        E: de::Error,
+    {
+        value.parse().map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_trace_id() -> Result<(), XRayError> {
+        let id: TraceId = "1-5759e988-bd862e3fe1be46a994272793".parse()?;
+        assert_eq!(id.epoch(), 0x5759e988);
+        assert_eq!(
+            id.random(),
+            &[0xbd, 0x86, 0x2e, 0x3f, 0xe1, 0xbe, 0x46, 0xa9, 0x94, 0x27, 0x27, 0x93]
+        );
+        assert_eq!(id.to_string(), "1-5759e988-bd862e3fe1be46a994272793");
+        assert_eq!(id, "1-5759E988-BD862E3FE1BE46A994272793".parse()?);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_trace_ids() {
+        let error = |s: &str| match s.parse::<TraceId>() {
+            Err(XRayError::InvalidId(e)) => e,
+            other => panic!("unexpected result for {}: {:?}", s, other),
+        };
+        assert_eq!(
+            error("1-5759e988-bd862e3fe1be46a99427279"),
+            IdError::Length {
+                expected: 35,
+                found: 34
+            }
+        );
+        assert_eq!(
+            error("2-5759e988-bd862e3fe1be46a994272793"),
+            IdError::Version
+        );
+        assert_eq!(
+            error("1-5759e988bbd862e3fe1be46a994272793"),
+            IdError::Separator { position: 10 }
+        );
+        assert_eq!(
+            error("1-5759e988-bd862e3fe1be46a99427279g"),
+            IdError::Digit { position: 34 }
+        );
+        assert_eq!(
+            error("1-5759e988-bd862e3fe1be46a9942727é"),
+            IdError::Digit { position: 33 }
+        );
+    }
+
+    #[test]
+    fn parses_segment_id() -> Result<(), XRayError> {
+        let id: SegmentId = "53995c3f42cd8ad8".parse()?;
+        assert_eq!(
+            id.as_bytes(),
+            &[0x53, 0x99, 0x5c, 0x3f, 0x42, 0xcd, 0x8a, 0xd8]
+        );
+        assert_eq!(id.to_string(), "53995c3f42cd8ad8");
+        assert!(matches!(
+            "53995c3f42cd8ad".parse::<SegmentId>(),
+            Err(XRayError::InvalidId(IdError::Length {
+                expected: 16,
+                found: 15
+            }))
+        ));
+        assert!(matches!(
+            "53995c3f-2cd8ad8".parse::<SegmentId>(),
+            Err(XRayError::InvalidId(IdError::Digit { position: 8 }))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_generated_ids() -> Result<(), XRayError> {
+        let trace_id = TraceId::new();
+        assert_eq!(trace_id, trace_id.to_string().parse()?);
+        let segment_id = SegmentId::new();
+        assert_eq!(segment_id, segment_id.to_string().parse()?);
+        Ok(())
+    }
+
+    #[test]
+    fn validates_when_deserializing() {
+        assert_eq!(
+            serde_json::from_str::<TraceId>("\"1-5759e988-bd862e3fe1be46a994272793\"").ok(),
+            "1-5759e988-bd862e3fe1be46a994272793".parse().ok()
+        );
+        assert!(serde_json::from_str::<SegmentId>("\"not an id\"").is_err());
     }
 }
@@ -1,7 +1,6 @@
+mod cause;
 pub mod header;
 pub mod ids;
 pub mod time;
+#[allow(clippy::module_inception)]
 pub mod types;
-
-pub use ids as _;
-pub use types as _;
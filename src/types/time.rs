@@ -10,7 +10,7 @@ use std::{
 ///
 /// A Default implementation is provided which yields the number of seconds since the epoch from
 /// the system time's `now` value
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub struct Seconds(pub(crate) f64);
 
 impl Seconds {
@@ -22,6 +22,11 @@ impl Seconds {
             .into()
     }
 
+    /// return the fractional seconds since the unix epoch
+    pub fn as_f64(&self) -> f64 {
+        self.0
+    }
+
     /// truncate epoc time to remove fractional seconds
     pub fn trunc(&self) -> u64 {
         self.0.trunc() as u64
@@ -40,9 +45,9 @@ impl From<Duration> for Seconds {
     }
 }
 
-impl Into<Duration> for Seconds {
-    fn into(self) -> Duration {
-        let Seconds(secs) = self;
+impl From<Seconds> for Duration {
+    fn from(seconds: Seconds) -> Self {
+        let Seconds(secs) = seconds;
         Duration::new(secs.trunc() as u64, (secs.fract() * 1.0e9) as u32)
     }
 }
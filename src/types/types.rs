@@ -1,9 +1,10 @@
 use super::{
+    header::{Header, Lineage},
     ids::{SegmentId, TraceId},
     time::Seconds,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Number, Value};
 use std::collections::HashMap;
 use std::fmt;
 use std::ops::Not;
@@ -75,13 +76,14 @@ pub struct Segment {
     /// A string that identifies the user who sent the request.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
-    ///
+    /// The ARN of the AWS resource running your application.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resource_arn: Option<String>,
     /// http objects with information about the original HTTP request.
-    /// #[serde(skip_serializing_if = "Option::is_none")] pub http:
-    /// Option<Http>, annotations object with key-value pairs that you want
-    /// X-Ray to index for search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http: Option<Http>,
+    /// annotations object with key-value pairs that you want X-Ray to index
+    /// for search.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub annotations: Option<HashMap<String, Annotation>>,
     /// metadata object with any additional data that you want to store in the
@@ -92,9 +94,32 @@ pub struct Segment {
     /// application served the request.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub aws: Option<Aws>,
+    /// (subsegments only) sql object with information about a query made to
+    /// an SQL database.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sql: Option<Sql>,
     /// An object with information about your application.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub service: Option<Service>,
+    /// (subsegments only) `subsegment`. Required only when a subsegment is sent
+    /// independently of its parent segment.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub(crate) kind: Option<Kind>,
+    /// (subsegments only) `aws` for AWS SDK calls, `remote` for other
+    /// downstream calls.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<Namespace>,
+    /// The lineage of the request, propagated downstream for recursive loop
+    /// detection
+    #[serde(skip)]
+    pub(crate) lineage: Option<Lineage>,
+    /// The number of completed subsegments held in a root segment's document,
+    /// including nested ones, counted as they close
+    #[serde(skip)]
+    pub(crate) held_subsegments: usize,
+    /// array of subsegment objects, representing work done within this segment.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub subsegments: Vec<Segment>,
 }
 
 impl Segment {
@@ -102,17 +127,162 @@ impl Segment {
     ///
     /// A segment's name should match the domain name or logical name of the service that generates the segment. However, this is not enforced. Any application that has permission to PutTraceSegments can send segments with any name.
     pub fn begin<N>(name: N) -> Self
+    where
+        N: Into<String>,
+    {
+        let mut segment = Segment::default();
+        segment.rename(name);
+        segment
+    }
+
+    /// Replaces the segment's name, truncated to 200 characters
+    pub fn rename<N>(&mut self, name: N) -> &mut Self
     where
         N: Into<String>,
     {
         let mut valid_name = name.into();
-        if valid_name.len() > 200 {
-            valid_name = valid_name[..200].into();
+        if let Some((end, _)) = valid_name.char_indices().nth(200) {
+            valid_name.truncate(end);
         }
+        self.name = valid_name;
+        self
+    }
+
+    /// Records the status of the HTTP response, marking the segment as an
+    /// error for 4XX statuses, additionally as throttled for 429, and as a
+    /// fault for 5XX statuses
+    pub fn set_response_status(&mut self, status: u16) -> &mut Self {
+        match status {
+            429 => {
+                self.error = true;
+                self.throttle = true;
+            }
+            400..=499 => self.error = true,
+            500..=599 => self.fault = true,
+            _ => {}
+        }
+        self.http
+            .get_or_insert_with(Http::default)
+            .response
+            .get_or_insert_with(Response::default)
+            .status = Some(status);
+        self
+    }
+
+    /// Begins a new named subsegment of `parent`
+    ///
+    /// The subsegment shares the parent's trace id and records the parent's id
+    /// so that it may be sent independently of the parent segment document.
+    pub fn begin_subsegment<N>(name: N, parent: &Segment) -> Self
+    where
+        N: Into<String>,
+    {
         Segment {
-            name: valid_name,
-            ..Segment::default()
+            trace_id: parent.trace_id,
+            parent_id: Some(parent.id),
+            kind: Some(Kind::Subsegment),
+            lineage: parent.lineage,
+            ..Segment::begin(name)
+        }
+    }
+
+    /// Begins a new named segment continuing the trace of an upstream
+    /// `X-Amzn-Trace-Id` header, as a child of the header's parent
+    pub fn begin_from_header<N>(name: N, header: &Header) -> Self
+    where
+        N: Into<String>,
+    {
+        Segment {
+            trace_id: *header.trace_id(),
+            parent_id: header.parent_id().cloned(),
+            lineage: header.lineage(),
+            ..Segment::begin(name)
+        }
+    }
+
+    /// The id of the trace the segment belongs to
+    pub fn trace_id(&self) -> &TraceId {
+        &self.trace_id
+    }
+
+    /// The id of the segment, unique within its trace
+    pub fn id(&self) -> &SegmentId {
+        &self.id
+    }
+
+    /// The logical name of the service or operation the segment records
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The time the segment began
+    pub fn start_time(&self) -> &Seconds {
+        &self.start_time
+    }
+
+    /// The type of the document, set for subsegments
+    pub fn kind(&self) -> Option<Kind> {
+        self.kind
+    }
+
+    /// Returns true if this document is a subsegment of another segment
+    pub fn is_subsegment(&self) -> bool {
+        self.kind == Some(Kind::Subsegment)
+    }
+
+    /// Adds an annotation, which X-Ray indexes for use with filter expressions
+    ///
+    /// Annotation keys may contain only alphanumeric characters and
+    /// underscores, up to 500 characters. Any other character is replaced with
+    /// an underscore and empty keys are ignored.
+    pub fn annotate<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: AsRef<str>,
+        V: Into<Annotation>,
+    {
+        let key = key
+            .as_ref()
+            .chars()
+            .take(500)
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        if !key.is_empty() {
+            self.annotations
+                .get_or_insert_with(HashMap::new)
+                .insert(key, value.into());
         }
+        self
+    }
+
+    /// Adds metadata under `key` within `namespace`. Metadata is not indexed,
+    /// but may hold any JSON value
+    pub fn insert_metadata<N, K, V>(&mut self, namespace: N, key: K, value: V) -> &mut Self
+    where
+        N: Into<String>,
+        K: Into<String>,
+        V: Into<Value>,
+    {
+        let namespace = self
+            .metadata
+            .get_or_insert_with(HashMap::new)
+            .entry(namespace.into())
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !namespace.is_object() {
+            *namespace = Value::Object(Map::new());
+        }
+        if let Value::Object(entries) = namespace {
+            entries.insert(key.into(), value.into());
+        }
+        self
+    }
+
+    /// The number of subsegments held by the segment, including nested ones,
+    /// found by walking them
+    pub(crate) fn subsegment_count(&self) -> usize {
+        self.subsegments
+            .iter()
+            .map(|subsegment| 1 + subsegment.subsegment_count())
+            .sum()
     }
 
     /// End the segment by assigning its end_time
@@ -123,19 +293,85 @@ impl Segment {
     }
 }
 
+/// The type of a segment document
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Kind {
+    /// A subsegment, recording work done on behalf of a parent segment
+    Subsegment,
+}
+
+/// The kind of downstream call a subsegment records
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Namespace {
+    /// A call to an AWS service made with an AWS SDK
+    Aws,
+    /// A call to any other downstream service
+    Remote,
+}
+
 /// A value type which may be used for
 /// filter querying
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
+#[non_exhaustive]
 pub enum Annotation {
     /// A string value
     String(String),
-    /// A numberic value
-    Number(usize),
+    /// A numeric value
+    Number(Number),
     /// A boolean value
     Bool(bool),
 }
 
+impl From<String> for Annotation {
+    fn from(value: String) -> Self {
+        Annotation::String(value)
+    }
+}
+
+impl From<&str> for Annotation {
+    fn from(value: &str) -> Self {
+        Annotation::String(value.into())
+    }
+}
+
+impl From<usize> for Annotation {
+    fn from(value: usize) -> Self {
+        Annotation::Number(value.into())
+    }
+}
+
+impl From<u64> for Annotation {
+    fn from(value: u64) -> Self {
+        Annotation::Number(value.into())
+    }
+}
+
+impl From<i64> for Annotation {
+    fn from(value: i64) -> Self {
+        Annotation::Number(value.into())
+    }
+}
+
+impl From<f64> for Annotation {
+    /// Non-finite values, which JSON cannot represent, are recorded as strings
+    fn from(value: f64) -> Self {
+        Number::from_f64(value)
+            .map(Annotation::Number)
+            .unwrap_or_else(|| Annotation::String(value.to_string()))
+    }
+}
+
+impl From<bool> for Annotation {
+    fn from(value: bool) -> Self {
+        Annotation::Bool(value)
+    }
+}
+
 impl Default for Annotation {
     fn default() -> Self {
         Annotation::String("".into())
@@ -144,6 +380,7 @@ impl Default for Annotation {
 
 /// Describes an http request/response cycle
 #[derive(Debug, Serialize, Deserialize, Default)]
+#[non_exhaustive]
 pub struct Http {
     /// Information about a request
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -155,6 +392,7 @@ pub struct Http {
 
 ///  Information about a request.
 #[derive(Debug, Serialize, Deserialize, Default)]
+#[non_exhaustive]
 pub struct Request {
     /// The request method. For example, GET.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -170,7 +408,7 @@ pub struct Request {
     pub user_agent: Option<String>,
     /// (segments only) boolean indicating that the client_ip was read from an X-Forwarded-For header and is not reliable as it could have been forged.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub x_forwarded_for: Option<String>,
+    pub x_forwarded_for: Option<bool>,
     /// (subsegments only) boolean indicating that the downstream call is to another traced service. If this field is set to true, X-Ray considers the trace to be broken until the downstream service uploads a segment with a parent_id that matches the id of the subsegment that contains this block.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub traced: Option<bool>,
@@ -178,6 +416,7 @@ pub struct Request {
 
 ///  Information about a response.
 #[derive(Debug, Serialize, Deserialize, Default)]
+#[non_exhaustive]
 pub struct Response {
     /// number indicating the HTTP status of the response.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -187,8 +426,54 @@ pub struct Response {
     pub content_length: Option<u64>,
 }
 
+///  Information about a query made to an SQL database.
+///
+/// Recorded from the `db.system`, `db.statement`, `db.url`,
+/// `db.connection_string`, `db.user`, `db.version`, `db.driver_version` and
+/// `db.preparation` fields of a span.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct Sql {
+    /// For SQL Server or other database connections that don't use URL connection strings, record the connection string, excluding passwords.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_string: Option<String>,
+    /// For a database connection that uses a URL connection string, record the URL, excluding passwords.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The database query, with any user provided values removed or replaced by a placeholder.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sanitized_query: Option<String>,
+    /// The name of the database engine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database_type: Option<String>,
+    /// The version number of the database engine.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database_version: Option<String>,
+    /// The name and version number of the database engine driver that your application uses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub driver_version: Option<String>,
+    /// The database username.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// `call` if the query used a PreparedCall; `statement` if the query used a PreparedStatement.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preparation: Option<Preparation>,
+}
+
+/// How an SQL query was prepared
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Preparation {
+    /// The query used a prepared call
+    Call,
+    /// The query used a prepared statement
+    Statement,
+}
+
 ///  An object with information about your application.
 #[derive(Debug, Default, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Service {
     /// A string that identifies the version of your application that served the request.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -197,6 +482,7 @@ pub struct Service {
 
 /// Context information about the AWS environment this segment was run in
 #[derive(Debug, Default, Deserialize, Serialize)]
+#[non_exhaustive]
 pub struct Aws {
     ///  If your application sends segments to a different AWS account, record the ID of the account running your application.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -213,22 +499,48 @@ pub struct Aws {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tracing: Option<Tracing>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub xray: Option<XRay>,
+    pub xray: Option<XRaySdk>,
+    /// (subsegments only) The name of the API action invoked against an AWS service or resource.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation: Option<String>,
+    /// (subsegments only) If the resource is in a region different from your application, record the region. For example, us-west-2.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// (subsegments only) Unique identifier for the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// (subsegments only) The number of times the call was retried.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    /// (subsegments only) For operations on an Amazon DynamoDB table, the name of the table.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub table_name: Option<String>,
+    /// (subsegments only) For operations on an Amazon SQS queue, the queue's URL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_url: Option<String>,
 }
 
+/// Information about the X-Ray SDK recording the segment
 #[derive(Debug, Default, Serialize, Deserialize)]
-pub struct XRay {
+#[non_exhaustive]
+pub struct XRaySdk {
+    /// The version of the SDK
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sdk_version: Option<String>,
 }
 
+/// Information about an Amazon ECS container.
 #[derive(Debug, Default, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Ecs {
     /// The container ID of the container running your application.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub container: Option<String>,
 }
 
+/// Information about an EC2 instance.
 #[derive(Debug, Default, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Ec2 {
     /// The instance ID of the EC2 instance.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -240,6 +552,7 @@ pub struct Ec2 {
 
 /// Information about an Elastic Beanstalk environment. You can find this information in a file named /var/elasticbeanstalk/xray/environment.conf on the latest Elastic Beanstalk platforms.
 #[derive(Debug, Default, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct ElasticBeanstalk {
     /// The name of the environment.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -252,7 +565,9 @@ pub struct ElasticBeanstalk {
     pub deployment_id: Option<usize>,
 }
 
+/// Information about the tracing SDK recording the segment
 #[derive(Debug, Default, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Tracing {
     /// version of sdk
     pub sdk: String,
@@ -260,13 +575,17 @@ pub struct Tracing {
 
 /// Detailed representation of an exception
 #[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Exception {
     /// A 64-bit identifier for the exception, unique among segments in the same trace, in 16 hexadecimal digits.
     pub id: String,
     /// The exception message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub messages: Option<String>,
+    pub message: Option<String>,
     /// The exception type.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// boolean indicating that the exception was caused by an error returned by a downstream service.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub remote: Option<bool>,
     /// integer indicating the number of stack frames that are omitted from the stack.
@@ -283,14 +602,15 @@ pub struct Exception {
 }
 
 /// A summary of a single operation within a stack trace
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct StackFrame {
     /// The relative path to the file.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
     /// The line in the file.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub line: Option<String>,
+    pub line: Option<u32>,
     /// The function or method name.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
@@ -299,10 +619,12 @@ pub struct StackFrame {
 /// Represents the cause of an errror
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
+#[non_exhaustive]
 pub enum Cause {
     ///  a 16 character exception ID
     Name(String),
     /// A description of an error
+    #[non_exhaustive]
     Description {
         ///  The full path of the working directory when the exception occurred.
         working_directory: String,
@@ -313,6 +635,156 @@ pub enum Cause {
     },
 }
 
+/// Implements builder methods which set optional fields of a model type
+macro_rules! builder {
+    ($ty:ident { $($(#[$doc:meta])* $method:ident => $field:ident: $value:ty),* $(,)? }) => {
+        impl $ty {
+            $(
+                $(#[$doc])*
+                #[allow(clippy::useless_conversion)]
+                pub fn $method(mut self, value: $value) -> Self {
+                    self.$field = Some(value.into());
+                    self
+                }
+            )*
+        }
+    };
+}
+
+builder!(Http {
+    /// Sets information about the request
+    with_request => request: Request,
+    /// Sets information about the response
+    with_response => response: Response,
+});
+
+builder!(Request {
+    /// Sets the request method
+    with_method => method: impl Into<String>,
+    /// Sets the full URL of the request
+    with_url => url: impl Into<String>,
+    /// Sets the IP address of the requester
+    with_client_ip => client_ip: impl Into<String>,
+    /// Sets the user agent string of the requester's client
+    with_user_agent => user_agent: impl Into<String>,
+    /// Sets whether the client IP was read from an X-Forwarded-For header
+    with_x_forwarded_for => x_forwarded_for: bool,
+    /// Sets whether the downstream call is to another traced service
+    with_traced => traced: bool,
+});
+
+builder!(Response {
+    /// Sets the HTTP status of the response
+    with_status => status: u16,
+    /// Sets the length of the response body in bytes
+    with_content_length => content_length: u64,
+});
+
+builder!(Sql {
+    /// Sets the connection string, which must not include passwords
+    with_connection_string => connection_string: impl Into<String>,
+    /// Sets the connection URL, which must not include passwords
+    with_url => url: impl Into<String>,
+    /// Sets the query, with user provided values removed
+    with_sanitized_query => sanitized_query: impl Into<String>,
+    /// Sets the name of the database engine
+    with_database_type => database_type: impl Into<String>,
+    /// Sets the version of the database engine
+    with_database_version => database_version: impl Into<String>,
+    /// Sets the name and version of the database driver
+    with_driver_version => driver_version: impl Into<String>,
+    /// Sets the database username
+    with_user => user: impl Into<String>,
+    /// Sets how the query was prepared
+    with_preparation => preparation: Preparation,
+});
+
+builder!(Service {
+    /// Sets the version of the application
+    with_version => version: impl Into<String>,
+});
+
+builder!(Aws {
+    /// Sets the ID of the account running the application
+    with_account_id => account_id: impl Into<String>,
+    /// Sets information about the Amazon ECS container
+    with_ecs => ecs: Ecs,
+    /// Sets information about the EC2 instance
+    with_ec2 => ec2: Ec2,
+    /// Sets information about the Elastic Beanstalk environment
+    with_elastic_beanstalk => elastic_beanstalk: ElasticBeanstalk,
+    /// Sets information about the tracing SDK
+    with_tracing => tracing: Tracing,
+    /// Sets information about the X-Ray SDK
+    with_xray => xray: XRaySdk,
+    /// Sets the name of the API action invoked
+    with_operation => operation: impl Into<String>,
+    /// Sets the region of the resource
+    with_region => region: impl Into<String>,
+    /// Sets the unique identifier for the request
+    with_request_id => request_id: impl Into<String>,
+    /// Sets the number of times the call was retried
+    with_retries => retries: u32,
+    /// Sets the name of the DynamoDB table
+    with_table_name => table_name: impl Into<String>,
+    /// Sets the URL of the SQS queue
+    with_queue_url => queue_url: impl Into<String>,
+});
+
+builder!(XRaySdk {
+    /// Sets the version of the SDK
+    with_sdk_version => sdk_version: impl Into<String>,
+});
+
+builder!(Ecs {
+    /// Sets the ID of the container
+    with_container => container: impl Into<String>,
+});
+
+builder!(Ec2 {
+    /// Sets the ID of the instance
+    with_instance_id => instance_id: impl Into<String>,
+    /// Sets the Availability Zone of the instance
+    with_availability_zone => availability_zone: impl Into<String>,
+});
+
+builder!(ElasticBeanstalk {
+    /// Sets the name of the environment
+    with_environment_name => environment_name: impl Into<String>,
+    /// Sets the name of the deployed application version
+    with_version_label => version_label: impl Into<String>,
+    /// Sets the ID of the last successful deployment
+    with_deployment_id => deployment_id: usize,
+});
+
+builder!(Exception {
+    /// Sets the exception type
+    with_kind => kind: impl Into<String>,
+    /// Sets whether the exception was caused by a downstream service
+    with_remote => remote: bool,
+    /// Sets the id of the exception which caused this exception
+    with_cause => cause: impl Into<String>,
+});
+
+builder!(StackFrame {
+    /// Sets the relative path to the file
+    with_path => path: impl Into<String>,
+    /// Sets the line in the file
+    with_line => line: u32,
+    /// Sets the function or method name
+    with_label => label: impl Into<String>,
+});
+
+impl Tracing {
+    /// Describes the tracing SDK by its version
+    pub fn new<S>(sdk: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Tracing { sdk: sdk.into() }
+    }
+}
+
 /// Wraps a byte slice to enable lowcast hex display formatting
 pub(crate) struct Bytes<'a>(pub(crate) &'a [u8]);
 
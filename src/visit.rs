@@ -0,0 +1,438 @@
+//! Field visitors used to extract X-Ray data from span attributes
+
+use crate::{
+    error::XRayError,
+    span_ext::DEFAULT_NAMESPACE,
+    types::{
+        header::Header,
+        types::{Annotation, Aws, Exception, Http, Namespace, Preparation, Request, Segment, Sql},
+    },
+};
+use serde_json::{Map, Value};
+use std::{collections::HashSet, error::Error, fmt};
+use tracing::field::{Field, Visit};
+
+/// Records the value of the span field carrying an upstream `X-Amzn-Trace-Id` header
+pub(crate) struct HeaderVisitor<'a> {
+    field: &'a str,
+    value: Option<String>,
+}
+
+impl<'a> HeaderVisitor<'a> {
+    pub(crate) fn new(field: &'a str) -> Self {
+        HeaderVisitor { field, value: None }
+    }
+
+    /// Parses the recorded header value, if any
+    pub(crate) fn header(&self) -> Option<Result<Header, XRayError>> {
+        self.value.as_deref().map(str::parse)
+    }
+
+    fn matches(&self, field: &Field) -> bool {
+        same_field_name(field.name(), self.field)
+    }
+}
+
+impl Visit for HeaderVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if self.matches(field) {
+            self.value = Some(value.into());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if self.matches(field) {
+            self.value = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// Records conventional `http.*` span fields into the X-Ray http block of a
+/// segment
+pub(crate) struct HttpVisitor<'a> {
+    segment: &'a mut Segment,
+}
+
+impl<'a> HttpVisitor<'a> {
+    pub(crate) fn new(segment: &'a mut Segment) -> Self {
+        HttpVisitor { segment }
+    }
+
+    fn request(&mut self) -> &mut Request {
+        self.segment
+            .http
+            .get_or_insert_with(Http::default)
+            .request
+            .get_or_insert_with(Request::default)
+    }
+
+    fn record(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "http.method" => self.request().method = Some(value),
+            "http.url" => self.request().url = Some(value),
+            "http.user_agent" => self.request().user_agent = Some(value),
+            "http.client_ip" => self.request().client_ip = Some(value),
+            "http.status_code" => {
+                if let Ok(status) = value.parse() {
+                    self.segment.set_response_status(status);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn record_status<T>(&mut self, field: &Field, value: T)
+    where
+        T: TryInto<u16>,
+    {
+        if field.name() == "http.status_code" {
+            if let Ok(status) = value.try_into() {
+                self.segment.set_response_status(status);
+            }
+        }
+    }
+}
+
+impl Visit for HttpVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        match field.name() {
+            // X-Ray only accepts traced requests on subsegments
+            "http.traced" if !self.segment.is_subsegment() => {}
+            "http.traced" => self.request().traced = Some(value),
+            "http.x_forwarded_for" => self.request().x_forwarded_for = Some(value),
+            _ => {}
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_status(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record_status(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+/// Records conventional `aws.*` span fields into the X-Ray aws block of a
+/// subsegment recording a call to an AWS service
+pub(crate) struct AwsVisitor<'a> {
+    segment: &'a mut Segment,
+}
+
+impl<'a> AwsVisitor<'a> {
+    pub(crate) fn new(segment: &'a mut Segment) -> Self {
+        AwsVisitor { segment }
+    }
+
+    fn aws(&mut self) -> &mut Aws {
+        self.segment.aws.get_or_insert_with(Aws::default)
+    }
+
+    fn record(&mut self, field: &Field, value: String) {
+        match field.name() {
+            "aws.operation" => self.aws().operation = Some(value),
+            "aws.region" => self.aws().region = Some(value),
+            "aws.request_id" => self.aws().request_id = Some(value),
+            "aws.table_name" => self.aws().table_name = Some(value),
+            "aws.queue_url" => self.aws().queue_url = Some(value),
+            "aws.account_id" => self.aws().account_id = Some(value),
+            _ => {}
+        }
+    }
+
+    fn record_retries<T>(&mut self, field: &Field, value: T)
+    where
+        T: TryInto<u32>,
+    {
+        if field.name() == "aws.retries" {
+            if let Ok(retries) = value.try_into() {
+                self.aws().retries = Some(retries);
+            }
+        }
+    }
+}
+
+impl Visit for AwsVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_retries(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record_retries(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+/// Records conventional `db.*` span fields into the X-Ray sql block of a
+/// subsegment recording a database query, marking it as a `remote` call
+pub(crate) struct SqlVisitor<'a> {
+    segment: &'a mut Segment,
+}
+
+impl<'a> SqlVisitor<'a> {
+    pub(crate) fn new(segment: &'a mut Segment) -> Self {
+        SqlVisitor { segment }
+    }
+
+    fn sql(&mut self) -> &mut Sql {
+        self.segment.namespace.get_or_insert(Namespace::Remote);
+        self.segment.sql.get_or_insert_with(Sql::default)
+    }
+
+    fn record(&mut self, field: &Field, value: String) {
+        // X-Ray only accepts sql blocks and namespaces on subsegments
+        if !self.segment.is_subsegment() {
+            return;
+        }
+        match field.name() {
+            "db.system" => self.sql().database_type = Some(value),
+            "db.statement" => self.sql().sanitized_query = Some(value),
+            "db.url" => self.sql().url = Some(value),
+            "db.connection_string" => self.sql().connection_string = Some(value),
+            "db.user" => self.sql().user = Some(value),
+            "db.version" => self.sql().database_version = Some(value),
+            "db.driver_version" => self.sql().driver_version = Some(value),
+            "db.preparation" => {
+                self.sql().preparation = match value.as_str() {
+                    "call" => Some(Preparation::Call),
+                    "statement" => Some(Preparation::Statement),
+                    _ => None,
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Visit for SqlVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+/// Decides which span fields are recorded as annotations or metadata
+pub(crate) struct FieldMapping {
+    pub(crate) annotation_prefix: String,
+    pub(crate) metadata_prefix: String,
+    pub(crate) annotation_fields: HashSet<String>,
+}
+
+impl Default for FieldMapping {
+    fn default() -> Self {
+        FieldMapping {
+            annotation_prefix: "xray.annotation.".into(),
+            metadata_prefix: "xray.metadata.".into(),
+            annotation_fields: HashSet::new(),
+        }
+    }
+}
+
+/// Where a span field is recorded, and under which key
+enum Target<'a> {
+    Annotation(&'a str),
+    Metadata(&'a str),
+}
+
+impl FieldMapping {
+    fn target<'a>(&self, name: &'a str) -> Option<Target<'a>> {
+        if let Some(key) = name.strip_prefix(self.annotation_prefix.as_str()) {
+            Some(Target::Annotation(key))
+        } else if let Some(key) = name.strip_prefix(self.metadata_prefix.as_str()) {
+            Some(Target::Metadata(key))
+        } else if self.annotation_fields.contains(name) {
+            Some(Target::Annotation(name))
+        } else {
+            None
+        }
+    }
+}
+
+/// Span field overriding the name of the span's segment
+pub(crate) const NAME_FIELD: &str = "xray.name";
+
+/// Span field setting the namespace of a subsegment, either `aws` or `remote`
+pub(crate) const NAMESPACE_FIELD: &str = "xray.namespace";
+
+/// Records span fields onto a segment as annotations or metadata
+///
+/// Strings, numbers and booleans become annotations, any other value is
+/// recorded as metadata in the `default` namespace. The `xray.name` field
+/// renames the segment, since span names must be known at compile time, and
+/// `xray.namespace` marks subsegments recording downstream calls.
+pub(crate) struct FieldVisitor<'a> {
+    mapping: &'a FieldMapping,
+    segment: &'a mut Segment,
+}
+
+impl<'a> FieldVisitor<'a> {
+    pub(crate) fn new(mapping: &'a FieldMapping, segment: &'a mut Segment) -> Self {
+        FieldVisitor { mapping, segment }
+    }
+
+    fn record<A>(&mut self, field: &Field, value: A)
+    where
+        A: Into<Annotation> + Into<Value>,
+    {
+        match self.mapping.target(field.name()) {
+            Some(Target::Annotation(key)) => {
+                self.segment.annotate(key, value);
+            }
+            Some(Target::Metadata(key)) => {
+                self.segment.insert_metadata(DEFAULT_NAMESPACE, key, value);
+            }
+            None => {}
+        }
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            NAME_FIELD => {
+                self.segment.rename(value);
+            }
+            // X-Ray only accepts namespaces on subsegments
+            NAMESPACE_FIELD if !self.segment.is_subsegment() => {}
+            NAMESPACE_FIELD => {
+                self.segment.namespace = match value {
+                    "aws" => Some(Namespace::Aws),
+                    "remote" => Some(Namespace::Remote),
+                    _ => None,
+                }
+            }
+            _ => self.record(field, value),
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, value);
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == NAME_FIELD {
+            self.segment.rename(format!("{:?}", value));
+            return;
+        }
+        let key = match self.mapping.target(field.name()) {
+            Some(Target::Annotation(key)) | Some(Target::Metadata(key)) => key,
+            None => return,
+        };
+        self.segment
+            .insert_metadata(DEFAULT_NAMESPACE, key, format!("{:?}", value));
+    }
+}
+
+/// Collects the fields of an event, separating out its `error` field as
+/// exceptions
+#[derive(Default)]
+pub(crate) struct EventVisitor {
+    pub(crate) fields: Map<String, Value>,
+    pub(crate) error: Option<Vec<Exception>>,
+}
+
+impl EventVisitor {
+    fn record<V>(&mut self, field: &Field, value: V)
+    where
+        V: Into<Value>,
+    {
+        self.fields.insert(field.name().into(), value.into());
+    }
+}
+
+impl Visit for EventVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "error" {
+            self.error = Some(vec![Exception::new(value)]);
+        }
+        self.record(field, value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, value);
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, value);
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+        if field.name() == "error" {
+            self.error = Some(Exception::chain(value));
+        }
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{:?}", value);
+        if field.name() == "error" {
+            self.error = Some(vec![Exception::new(value.clone())]);
+        }
+        self.record(field, value);
+    }
+}
+
+/// Compares field names ignoring ASCII case and treating `_` and `-` as equal,
+/// so that `X-Amzn-Trace-Id`, `x-amzn-trace-id` and `x_amzn_trace_id` all match
+fn same_field_name(name: &str, expected: &str) -> bool {
+    let normalize = |c: u8| match c {
+        b'_' => b'-',
+        c => c.to_ascii_lowercase(),
+    };
+    name.len() == expected.len()
+        && name
+            .bytes()
+            .zip(expected.bytes())
+            .all(|(a, b)| normalize(a) == normalize(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_header_name_variants() {
+        assert!(same_field_name("x-amzn-trace-id", Header::NAME));
+        assert!(same_field_name("x_amzn_trace_id", Header::NAME));
+        assert!(same_field_name("X-Amzn-Trace-Id", Header::NAME));
+        assert!(!same_field_name("x-amzn-trace", Header::NAME));
+    }
+}